@@ -4,14 +4,15 @@ use {
     crate::{
         Event,
         backend::{self, Request},
+        keymap::{Action, MprisAction},
         macro_command::{DeckInfo, MacroCommand},
+        state::{self, Store},
     },
-    cec_rs::{CecKeypress, CecUserControlCode},
     futures_util::{
         FutureExt, StreamExt, TryFutureExt, future::try_join_all, lock::Mutex as AsyncMutex, ready,
     },
     player::PlayerProxy,
-    std::{cmp::min, collections::HashMap, future::Future, pin::Pin, task::Poll},
+    std::{cmp::min, collections::HashMap, future::Future, pin::Pin, rc::Rc, task::Poll},
     zbus::{
         MatchRule, MessageStream,
         fdo::{DBusProxy, NameOwnerChanged},
@@ -25,13 +26,13 @@ pub struct Backend {
 }
 
 impl backend::Backend for Backend {
-    type Context = zbus::Connection;
+    type Context = Rc<Store>;
     type Error = zbus::Error;
     type Proxy<'a> = Proxy<'a>;
     type Stream<'a> = Stream<'a>;
 
-    async fn new(session: Self::Context) -> Result<Self, Self::Error> {
-        let players = Players::new(session).await?;
+    async fn new(state: Self::Context) -> Result<Self, Self::Error> {
+        let players = Players::new(zbus::Connection::session().await?, state).await?;
         Ok(Backend {
             players: AsyncMutex::new(players),
         })
@@ -52,103 +53,43 @@ pub struct Proxy<'a> {
 impl backend::Proxy for Proxy<'_> {
     type Error = zbus::Error;
 
-    async fn event(&mut self, event: &Event) -> Result<(), Self::Error> {
-        if let Event::KeyPress(CecKeypress { keycode, duration }) = event
-            && duration.is_zero()
-        {
-            match keycode {
-                CecUserControlCode::Play => {
-                    try_join_all(
-                        self.backend
-                            .players
-                            .try_lock()
-                            .unwrap()
-                            .iter()
-                            .map(|player| player.proxy.play()),
-                    )
-                    .await?;
-                }
-                CecUserControlCode::Pause => {
-                    try_join_all(
-                        self.backend
-                            .players
-                            .try_lock()
-                            .unwrap()
-                            .iter()
-                            .map(|player| player.proxy.play_pause()),
-                    )
-                    .await?;
-                }
-                CecUserControlCode::Stop => {
-                    try_join_all(
-                        self.backend
-                            .players
-                            .try_lock()
-                            .unwrap()
-                            .iter()
-                            .map(|player| player.proxy.stop()),
-                    )
-                    .await?;
-                }
-                CecUserControlCode::FastForward => {
-                    try_join_all(
-                        self.backend
-                            .players
-                            .try_lock()
-                            .unwrap()
-                            .iter()
-                            .map(|player| {
-                                player
-                                    .proxy
-                                    .pause()
-                                    .and_then(|_| player.proxy.seek(10000000))
-                            }),
-                    )
-                    .await?;
-                }
-                CecUserControlCode::Rewind => {
-                    try_join_all(
-                        self.backend
-                            .players
-                            .try_lock()
-                            .unwrap()
-                            .iter()
-                            .map(|player| {
-                                player
-                                    .proxy
-                                    .pause()
-                                    .and_then(|_| player.proxy.seek(-10000000))
-                            }),
-                    )
-                    .await?;
-                }
-                CecUserControlCode::Forward => {
-                    try_join_all(
-                        self.backend
-                            .players
-                            .try_lock()
-                            .unwrap()
-                            .iter()
-                            .map(|player| player.proxy.next()),
-                    )
-                    .await?;
+    async fn event(&mut self, _event: &Event) -> Result<Vec<Request>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    /// Claims MPRIS actions while there are media players
+    async fn action(&mut self, action: &Action) -> Result<Option<Vec<Request>>, Self::Error> {
+        let Action::Mpris(action) = action else {
+            return Ok(None);
+        };
+
+        let players = self.backend.players.try_lock().unwrap();
+        if players.iter().next().is_none() {
+            return Ok(None);
+        }
+
+        try_join_all(players.iter().map(|player| async move {
+            let proxy = &player.proxy;
+            match action {
+                MprisAction::Play => proxy.play().await,
+                MprisAction::Pause => proxy.pause().await,
+                MprisAction::PlayPause => proxy.play_pause().await,
+                MprisAction::Stop => proxy.stop().await,
+                MprisAction::Next => proxy.next().await,
+                MprisAction::Previous => proxy.previous().await,
+                MprisAction::SeekForward => {
+                    proxy.pause().await?;
+                    proxy.seek(10000000).await
                 }
-                CecUserControlCode::Backward => {
-                    try_join_all(
-                        self.backend
-                            .players
-                            .try_lock()
-                            .unwrap()
-                            .iter()
-                            .map(|player| player.proxy.previous()),
-                    )
-                    .await?;
+                MprisAction::SeekBackward => {
+                    proxy.pause().await?;
+                    proxy.seek(-10000000).await
                 }
-                _ => (),
             }
-        }
+        }))
+        .await?;
 
-        Ok(())
+        Ok(Some(Vec::new()))
     }
 }
 
@@ -178,13 +119,15 @@ impl futures_util::Stream for Stream<'_> {
 
 struct Players {
     session: zbus::Connection,
+    state: Rc<Store>,
     media_player_owner_changed: MessageStream,
     inner: HashMap<String, PlayerFuture>,
     deck_info: DeckInfo,
+    has_updates: bool,
 }
 
 impl Players {
-    async fn new(session: zbus::Connection) -> Result<Self, zbus::Error> {
+    async fn new(session: zbus::Connection, state: Rc<Store>) -> Result<Self, zbus::Error> {
         let media_player_owner_changed = MessageStream::for_match_rule(
             MatchRule::builder()
                 .msg_type(zbus::message::Type::Signal)
@@ -210,21 +153,36 @@ impl Players {
             .into_iter()
             .flat_map(|name| {
                 if name.as_str().starts_with("org.mpris.MediaPlayer2.") {
+                    let preferences = preferences(&state, name.as_str());
                     Some((
                         name.as_str().to_owned(),
-                        PlayerFuture::Pending(Box::pin(Player::new(&session, name.into())) as _),
+                        PlayerFuture::Pending(Box::pin(Player::new(
+                            &session,
+                            name.into(),
+                            preferences,
+                        )) as _),
                     ))
                 } else {
                     None
                 }
             })
-            .collect();
+            .collect::<HashMap<_, _>>();
+
+        // Start from the deck info that was last sent before a restart,
+        // so that it gets corrected if nothing is playing anymore
+        let deck_info = state.get().mpris.deck_info;
+
+        // Players will report their initial playback status, but if
+        // there aren't any, the deck info still needs to be checked
+        let has_updates = inner.is_empty();
 
         Ok(Self {
             session,
+            state,
             media_player_owner_changed,
             inner,
-            deck_info: DeckInfo::default(),
+            deck_info,
+            has_updates,
         })
     }
 
@@ -232,7 +190,7 @@ impl Players {
         &mut self,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Result<Request, zbus::Error>>> {
-        let mut has_updates = false;
+        let mut has_updates = std::mem::take(&mut self.has_updates);
         loop {
             match self.media_player_owner_changed.poll_next_unpin(cx)? {
                 Poll::Pending => break,
@@ -246,6 +204,7 @@ impl Players {
                         let future = PlayerFuture::Pending(Box::pin(Player::new(
                             &self.session,
                             args.name().to_owned(),
+                            preferences(&self.state, args.name().as_str()),
                         )));
 
                         self.inner.insert(args.name().as_str().to_owned(), future);
@@ -260,16 +219,12 @@ impl Players {
         for future in self.inner.values_mut() {
             loop {
                 if let Poll::Ready(player) = future.poll_as_mut_unpin(cx)? {
-                    match player.playback_status_changed.poll_next_unpin(cx) {
+                    match player.poll_changed(cx) {
                         Poll::Pending => break,
 
-                        // Property change streams hang when player is removed
-                        // Have to watch NameOwnerChanged events & manually remove
-                        Poll::Ready(None) => unreachable!(),
-
                         // We could break the outer loop early at this point, but
                         // we still want poll all the other futures, so set a flag
-                        Poll::Ready(Some(_)) => has_updates = true,
+                        Poll::Ready(()) => has_updates = true,
                     }
                 }
             }
@@ -287,7 +242,45 @@ impl Players {
                     },
                     _ => DeckInfo::Stop,
                 })
-                .fold(DeckInfo::Stop, |acc, status| min(acc, status));
+                .fold(DeckInfo::Stop, min);
+
+            let players = self
+                .inner
+                .iter()
+                .flat_map(|(name, player)| {
+                    let player = player.as_ref()?;
+                    Some((
+                        name.clone(),
+                        state::Player {
+                            playback_status: player
+                                .proxy
+                                .cached_playback_status()
+                                .ok()
+                                .flatten()
+                                .unwrap_or_default(),
+                        },
+                    ))
+                })
+                .collect();
+
+            let preferences = self
+                .iter_named()
+                .flat_map(|(name, player)| {
+                    let preferences = state::Preferences {
+                        shuffle: player.proxy.cached_shuffle().ok().flatten(),
+                        loop_status: player.proxy.cached_loop_status().ok().flatten(),
+                    };
+                    (preferences != state::Preferences::default())
+                        .then(|| (preferences_key(name).to_owned(), preferences))
+                })
+                .collect::<Vec<_>>();
+
+            self.state.update(|state| {
+                state.mpris.deck_info = deck_info;
+                state.mpris.players = players;
+                // Players that aren't running keep their preferences
+                state.mpris.preferences.extend(preferences);
+            });
 
             if self.deck_info != deck_info {
                 self.deck_info = deck_info;
@@ -301,6 +294,26 @@ impl Players {
     fn iter(&self) -> impl Iterator<Item = &Player> {
         self.inner.values().flat_map(PlayerFuture::as_ref)
     }
+
+    fn iter_named(&self) -> impl Iterator<Item = (&str, &Player)> {
+        self.inner
+            .iter()
+            .flat_map(|(name, player)| Some((name.as_str(), player.as_ref()?)))
+    }
+}
+
+/// Players running more than once get an `.instance<pid>` suffix, which
+/// isn't part of the name their preferences are saved under
+fn preferences_key(name: &str) -> &str {
+    match name.rsplit_once(".instance") {
+        Some((key, pid)) if pid.bytes().all(|byte| byte.is_ascii_digit()) => key,
+        _ => name,
+    }
+}
+
+fn preferences(state: &Store, name: &str) -> Option<state::Preferences> {
+    let state = state.get();
+    state.mpris.preferences.get(preferences_key(name)).cloned()
 }
 
 enum PlayerFuture {
@@ -339,25 +352,73 @@ impl PlayerFuture {
 struct Player {
     proxy: PlayerProxy<'static>,
     playback_status_changed: PropertyStream<'static, String>,
+    shuffle_changed: PropertyStream<'static, bool>,
+    loop_status_changed: PropertyStream<'static, String>,
 }
 
 impl Player {
     fn new(
         session: &zbus::Connection,
         destination: BusName<'static>,
+        preferences: Option<state::Preferences>,
     ) -> impl Future<Output = Result<Self, zbus::Error>> + use<> {
         PlayerProxy::builder(session)
             .destination(destination)
             .unwrap()
             .build()
-            .and_then(|proxy| Player::from_proxy(proxy).map(Ok))
+            .and_then(|proxy| async move {
+                if let Some(preferences) = preferences {
+                    Player::restore(&proxy, &preferences).await;
+                }
+                Ok(Player::from_proxy(proxy).await)
+            })
     }
 
     async fn from_proxy(proxy: PlayerProxy<'static>) -> Self {
         let playback_status_changed = proxy.receive_playback_status_changed().await;
+        let shuffle_changed = proxy.receive_shuffle_changed().await;
+        let loop_status_changed = proxy.receive_loop_status_changed().await;
         Self {
             proxy,
             playback_status_changed,
+            shuffle_changed,
+            loop_status_changed,
+        }
+    }
+
+    /// Restoring preferences is best effort, players may not allow
+    /// changing them
+    async fn restore(proxy: &PlayerProxy<'static>, preferences: &state::Preferences) {
+        let restore = async {
+            if let Some(shuffle) = preferences.shuffle {
+                proxy.set_shuffle(shuffle).await?;
+            }
+            if let Some(loop_status) = &preferences.loop_status {
+                proxy.set_loop_status(loop_status).await?;
+            }
+            Ok::<_, zbus::Error>(())
+        };
+
+        if let Err(err) = restore.await {
+            eprintln!(
+                "notice: mpris: failed to restore the preferences of {}: {err}, continuing...",
+                proxy.inner().destination()
+            );
+        }
+    }
+
+    /// Ready when any of the watched properties changed
+    fn poll_changed(&mut self, cx: &mut std::task::Context<'_>) -> Poll<()> {
+        // Property change streams hang when player is removed
+        // Have to watch NameOwnerChanged events & manually remove
+        let changed = [
+            self.playback_status_changed.poll_next_unpin(cx).is_ready(),
+            self.shuffle_changed.poll_next_unpin(cx).is_ready(),
+            self.loop_status_changed.poll_next_unpin(cx).is_ready(),
+        ];
+        match changed.contains(&true) {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
         }
     }
 }
@@ -1,19 +1,50 @@
+mod shutdown;
+
 use {
     crate::{
         backend::{self, Event, Request},
-        macro_command::{MacroCommand, Power},
+        config::{self, ResumeAction},
+        keymap::{Action, LogindAction},
+        macro_command::{Active, MacroCommand, Power},
+        scene::SceneCommand,
+        state::Store,
     },
     async_stream::try_stream,
-    cec_rs::{CecCommand, CecOpcode},
-    futures_util::StreamExt,
-    logind_zbus::manager::{InhibitType, ManagerProxy, PrepareForSleepStream},
-    std::cell::RefCell,
-    zbus::{proxy::CacheProperties, zvariant::OwnedFd},
+    futures_util::{
+        StreamExt,
+        stream::{self, LocalBoxStream},
+    },
+    logind_zbus::manager::{InhibitType, ManagerProxy},
+    std::{cell::RefCell, rc::Rc},
+    zbus::{fdo::IntrospectableProxy, proxy::CacheProperties, zvariant::OwnedFd},
 };
 
+/// Scene saved before going to sleep, for the `restore-scene` resume
+/// action
+const SLEEP_SCENE: &str = "before-sleep";
+
+/// Shutdown types that logind reports for reboots
+const REBOOTS: [&str; 3] = ["reboot", "kexec", "soft-reboot"];
+
+pub struct Context {
+    pub state: Rc<Store>,
+    pub sleep: config::Sleep,
+    pub resume: config::Resume,
+    pub power_off: config::PowerOff,
+    pub reboot: config::Reboot,
+}
+
 pub struct Backend {
     manager: ManagerProxy<'static>,
+    /// Only set if logind tells reboots and power offs apart
+    shutdown: Option<shutdown::ManagerProxy<'static>>,
     sleep_lock: RefCell<Option<OwnedFd>>,
+    shutdown_lock: RefCell<Option<OwnedFd>>,
+    store: Rc<Store>,
+    sleep: config::Sleep,
+    resume: config::Resume,
+    power_off: config::PowerOff,
+    reboot: config::Reboot,
 }
 
 impl Backend {
@@ -27,34 +58,173 @@ impl Backend {
             )
             .await
     }
+
+    async fn shutdown_lock(manager: &ManagerProxy<'static>) -> Result<OwnedFd, zbus::Error> {
+        manager
+            .inhibit(
+                InhibitType::Shutdown,
+                "cec-sync",
+                "Signal shutdown event to CEC devices before shutting down",
+                "delay",
+            )
+            .await
+    }
+
+    /// `PrepareForShutdown` is sent for any shutdown, older versions of
+    /// logind don't have `PrepareForShutdownWithMetadata`
+    async fn shutdown_proxy(
+        system: &zbus::Connection,
+    ) -> Result<Option<shutdown::ManagerProxy<'static>>, zbus::Error> {
+        let introspectable = IntrospectableProxy::builder(system)
+            .destination("org.freedesktop.login1")?
+            .path("/org/freedesktop/login1")?
+            .build()
+            .await?;
+        if !introspectable
+            .introspect()
+            .await?
+            .contains("PrepareForShutdownWithMetadata")
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(shutdown::ManagerProxy::new(system).await?))
+    }
+
+    /// Signals that logind is preparing for sleep or shutdown
+    async fn prepare(
+        &self,
+    ) -> Result<LocalBoxStream<'static, Result<Prepare, zbus::Error>>, zbus::Error> {
+        let sleep = self
+            .manager
+            .receive_prepare_for_sleep()
+            .await?
+            .map(|signal| Ok(Prepare::Sleep(signal.args()?.start)));
+
+        let shutdown = match &self.shutdown {
+            Some(shutdown) => shutdown
+                .receive_prepare_for_shutdown_with_metadata()
+                .await?
+                .map(|signal| {
+                    let args = signal.args()?;
+                    let reboot = args
+                        .metadata
+                        .get("type")
+                        .and_then(|kind| kind.downcast_ref::<&str>().ok())
+                        .is_some_and(|kind| REBOOTS.contains(&kind));
+                    Ok(Prepare::Shutdown {
+                        start: args.start,
+                        reboot,
+                    })
+                })
+                .boxed_local(),
+            // Reboots can't be told apart, so they're power offs too
+            None => self
+                .manager
+                .receive_prepare_for_shutdown()
+                .await?
+                .map(|signal| {
+                    Ok(Prepare::Shutdown {
+                        start: signal.args()?.start,
+                        reboot: false,
+                    })
+                })
+                .boxed_local(),
+        };
+
+        Ok(stream::select(sleep, shutdown).boxed_local())
+    }
+
+    /// The scene is saved even if the TV already went to standby, so
+    /// that an older one isn't restored
+    fn sleep_requests(&self, tv_off: bool) -> Vec<Request> {
+        let mut requests = Vec::new();
+        if self.resume.actions.contains(&ResumeAction::RestoreScene) {
+            requests.push(Request::Scene(SceneCommand::Save {
+                name: String::from(SLEEP_SCENE),
+            }));
+        }
+
+        // There's nothing to signal if the TV already went to standby,
+        // eg. when this device is following it
+        if !tv_off {
+            let actions = self.sleep.actions.iter().copied();
+            requests.extend(actions.map(|action| Request::Macro(action.into())));
+        }
+        requests
+    }
+
+    fn shutdown_requests(&self, reboot: bool) -> Vec<Request> {
+        let actions = match reboot {
+            true => &self.reboot.actions,
+            false => &self.power_off.actions,
+        };
+        let actions = actions.iter().copied();
+        actions
+            .map(|action| Request::Macro(action.into()))
+            .collect()
+    }
+
+    fn resume_requests(&self) -> Vec<Request> {
+        let actions = self.resume.actions.iter();
+        actions
+            .map(|action| match action {
+                ResumeAction::PowerOnTv => {
+                    Request::Macro(MacroCommand::Power(Power::On { tv: true }))
+                }
+                ResumeAction::ActiveSource => {
+                    Request::Macro(MacroCommand::Active(Active::Set { cooperative: false }))
+                }
+                ResumeAction::RestoreScene => Request::Scene(SceneCommand::Restore {
+                    name: String::from(SLEEP_SCENE),
+                }),
+            })
+            .collect()
+    }
 }
 
 impl backend::Backend for Backend {
-    type Context = zbus::Connection;
+    type Context = Context;
     type Error = zbus::Error;
     type Proxy<'a> = Proxy<'a>;
     type Stream<'a> = Stream<'a>;
 
-    async fn new(system: Self::Context) -> Result<Self, Self::Error> {
+    async fn new(ctx: Self::Context) -> Result<Self, Self::Error> {
+        let system = zbus::Connection::system().await?;
         let manager = ManagerProxy::builder(&system)
             .cache_properties(CacheProperties::No)
             .build()
             .await?;
 
         let sleep_lock = RefCell::new(Some(Self::sleep_lock(&manager).await?));
+        let shutdown_lock = RefCell::new(Some(Self::shutdown_lock(&manager).await?));
         Ok(Self {
+            shutdown: Self::shutdown_proxy(&system).await?,
             manager,
             sleep_lock,
+            shutdown_lock,
+            store: ctx.state,
+            sleep: ctx.sleep,
+            resume: ctx.resume,
+            power_off: ctx.power_off,
+            reboot: ctx.reboot,
         })
     }
 
+    async fn reload(&mut self, ctx: Self::Context) -> Result<(), Self::Error> {
+        self.sleep = ctx.sleep;
+        self.resume = ctx.resume;
+        self.power_off = ctx.power_off;
+        self.reboot = ctx.reboot;
+        Ok(())
+    }
+
     async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error> {
-        let prepare_for_sleep = self.manager.receive_prepare_for_sleep().await?;
         Ok((
             Self::Proxy { backend: self },
             Self::Stream {
                 backend: self,
-                prepare_for_sleep,
+                prepare: self.prepare().await?,
             },
         ))
     }
@@ -67,38 +237,48 @@ pub struct Proxy<'a> {
 impl backend::Proxy for Proxy<'_> {
     type Error = zbus::Error;
 
-    async fn event(&mut self, event: &Event) -> Result<(), Self::Error> {
-        match event {
-            Event::Command(command) => match command {
-                CecCommand {
-                    opcode: CecOpcode::Standby,
-                    ..
-                } => {
-                    self.backend.sleep_lock.replace(None);
-                    match self.backend.manager.suspend(false).await {
-                        Ok(()) => (),
-                        Err(err) => match err {
-                            zbus::Error::MethodError(name, _detail, _reply)
-                                if name == "org.freedesktop.login1.OperationInProgress" =>
-                            {
-                                ()
-                            }
-                            _ => return Err(err),
-                        },
-                    }
-                }
-                _ => (),
-            },
-            _ => (),
+    /// Standby from other devices is followed by the standby backend,
+    /// with logind actions
+    async fn event(&mut self, _: &Event) -> Result<Vec<Request>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    async fn action(&mut self, action: &Action) -> Result<Option<Vec<Request>>, Self::Error> {
+        let Action::Logind(action) = action else {
+            return Ok(None);
+        };
+
+        let manager = &self.backend.manager;
+        let result = match action {
+            LogindAction::Suspend => manager.suspend(false).await,
+            LogindAction::Hibernate => manager.hibernate(false).await,
+            LogindAction::SuspendThenHibernate => manager.suspend_then_hibernate(false).await,
+            LogindAction::PowerOff => manager.power_off(false).await,
+            LogindAction::Reboot => manager.reboot(false).await,
+            LogindAction::LockSessions => manager.lock_sessions().await,
+        };
+        match result {
+            Ok(()) => (),
+            // eg. a key pressed again while the device is going to sleep
+            Err(zbus::Error::MethodError(name, _detail, _reply))
+                if name == "org.freedesktop.login1.OperationInProgress" => {}
+            Err(err) => return Err(err),
         }
 
-        Ok(())
+        Ok(Some(Vec::new()))
     }
 }
 
+/// What logind is preparing for, with `true` before it happens and
+/// `false` after (or if it was cancelled)
+enum Prepare {
+    Sleep(bool),
+    Shutdown { start: bool, reboot: bool },
+}
+
 pub struct Stream<'a> {
     backend: &'a Backend,
-    prepare_for_sleep: PrepareForSleepStream,
+    prepare: LocalBoxStream<'static, Result<Prepare, zbus::Error>>,
 }
 
 impl backend::Stream for Stream<'_> {
@@ -106,25 +286,53 @@ impl backend::Stream for Stream<'_> {
 
     fn into_stream(mut self) -> impl futures_util::Stream<Item = Result<Request, Self::Error>> {
         Box::pin(try_stream! {
-            while let Some(event) = self.prepare_for_sleep.next().await {
-                match event.args()?.start {
-                    true => {
+            while let Some(prepare) = self.prepare.next().await {
+                match prepare? {
+                    Prepare::Sleep(true) => {
+                        let tv_off = self.backend.store.get().tv_power == Some(false);
                         if self.backend.sleep_lock.borrow().is_some() {
-                            yield Request::Macro(MacroCommand::Power(Power::Off {
-                                cooperative: true,
-                            }));
+                            for request in self.backend.sleep_requests(tv_off) {
+                                yield request;
+                            }
                         }
+
+                        // The stream is only polled again once the last
+                        // request was handled, so the standby was sent (and
+                        // acknowledged, or its failure logged) by now and
+                        // the device can sleep
+                        self.backend.sleep_lock.replace(None);
                     }
-                    false => {
+                    Prepare::Sleep(false) => {
                         // After resuming from sleep, libcec gets stuck in an
                         // infinite retry loop if we send MacroCommand::Active,
-                        // so just reset the connection instead
+                        // so reset the connection before running any action
                         yield Request::ResetDevice(None);
+                        for request in self.backend.resume_requests() {
+                            yield request;
+                        }
 
                         self.backend
                             .sleep_lock
                             .replace(Some(Backend::sleep_lock(&self.backend.manager).await?));
                     }
+                    Prepare::Shutdown {
+                        start: true,
+                        reboot,
+                    } => {
+                        if self.backend.shutdown_lock.borrow().is_some() {
+                            for request in self.backend.shutdown_requests(reboot) {
+                                yield request;
+                            }
+                        }
+
+                        // Like for sleep, the requests were handled by now
+                        self.backend.shutdown_lock.replace(None);
+                    }
+                    Prepare::Shutdown { start: false, .. } => {
+                        self.backend
+                            .shutdown_lock
+                            .replace(Some(Backend::shutdown_lock(&self.backend.manager).await?));
+                    }
                 }
             }
         })
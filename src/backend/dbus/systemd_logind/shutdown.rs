@@ -0,0 +1,23 @@
+//! # D-Bus interface proxy for: `org.freedesktop.login1.Manager`
+//!
+//! Only the `PrepareForShutdownWithMetadata` signal, added in systemd 255,
+//! which isn't in logind-zbus yet.
+use {
+    std::collections::HashMap,
+    zbus::{proxy, zvariant::OwnedValue},
+};
+
+#[proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+pub trait Manager {
+    /// PrepareForShutdownWithMetadata signal
+    #[zbus(signal)]
+    fn prepare_for_shutdown_with_metadata(
+        &self,
+        start: bool,
+        metadata: HashMap<String, OwnedValue>,
+    ) -> zbus::Result<()>;
+}
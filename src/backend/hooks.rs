@@ -0,0 +1,223 @@
+use {
+    crate::{
+        backend::{
+            self, Event, Request,
+            observer::{Details, Observer},
+        },
+        config, sd_notify,
+    },
+    async_channel::{Receiver, Sender},
+    async_io::{Timer, block_on},
+    async_process::{Command, Stdio},
+    blocking::{Task, unblock},
+    futures_util::{
+        AsyncWriteExt, StreamExt,
+        future::{Either, select},
+        stream::FuturesUnordered,
+    },
+    serde_json::Value,
+    std::{
+        convert::Infallible,
+        io,
+        pin::pin,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    },
+};
+
+/// Runs the configured hooks when something happens on the bus
+///
+/// Hooks get the details of the event as `CEC_SYNC_*` environment
+/// variables, and as a JSON object on stdin. They run on a thread of
+/// their own, so they go on while the service handles requests, and
+/// the ones still running are killed when the backend is dropped.
+pub struct Backend {
+    config: config::Hooks,
+    tx: Sender<Job>,
+    /// Shared with the runner, so a reload can change it
+    max_concurrent: Arc<AtomicUsize>,
+    /// Closed to stop the runner
+    stop: Sender<()>,
+    runner: Option<Task<()>>,
+    observer: Observer,
+}
+
+impl backend::Backend for Backend {
+    type Context = config::Hooks;
+    type Error = Infallible;
+    type Proxy<'a> = Proxy<'a>;
+    type Stream<'a> = ();
+
+    async fn new(config: Self::Context) -> Result<Self, Self::Error> {
+        let (tx, rx) = async_channel::unbounded();
+        let (stop, stopped) = async_channel::bounded(1);
+        let max_concurrent = Arc::new(AtomicUsize::new(config.max_concurrent.max(1)));
+        let runner = unblock({
+            let max_concurrent = max_concurrent.clone();
+            move || block_on(run(rx, stopped, max_concurrent))
+        });
+
+        Ok(Self {
+            config,
+            tx,
+            max_concurrent,
+            stop,
+            runner: Some(runner),
+            observer: Observer::default(),
+        })
+    }
+
+    async fn reload(&mut self, config: Self::Context) -> Result<(), Self::Error> {
+        self.max_concurrent
+            .store(config.max_concurrent.max(1), Ordering::Relaxed);
+        self.config = config;
+        Ok(())
+    }
+
+    async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error> {
+        Ok((Self::Proxy { backend: self }, ()))
+    }
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        self.stop.close();
+        if let Some(runner) = self.runner.take() {
+            // Only waits for the running hooks to be killed
+            block_on(runner);
+        }
+    }
+}
+
+pub struct Proxy<'a> {
+    backend: &'a Backend,
+}
+
+impl backend::Proxy for Proxy<'_> {
+    type Error = Infallible;
+
+    async fn event(&mut self, event: &Event) -> Result<Vec<Request>, Self::Error> {
+        let config = &self.backend.config;
+
+        for details in self.backend.observer.observe(event) {
+            for hook in config.hook.iter().filter(|hook| hook.on == details.event) {
+                // The receiver lives as long as the backend
+                let _ = self.backend.tx.try_send(Job {
+                    command: hook.command.clone(),
+                    timeout: Duration::from_millis(hook.timeout_ms.unwrap_or(config.timeout_ms)),
+                    details: details.clone(),
+                });
+            }
+        }
+
+        Ok(Vec::new())
+    }
+}
+
+/// Run the queued hooks, at most `max_concurrent` at once, until the
+/// backend is dropped, killing the ones still running then
+async fn run(jobs: Receiver<Job>, stopped: Receiver<()>, max_concurrent: Arc<AtomicUsize>) {
+    let run = async {
+        let mut running = FuturesUnordered::new();
+        loop {
+            if running.len() >= max_concurrent.load(Ordering::Relaxed) {
+                running.next().await;
+                continue;
+            }
+
+            let job = match running.is_empty() {
+                true => jobs.recv().await,
+                false => match select(pin!(jobs.recv()), running.next()).await {
+                    Either::Left((job, _)) => job,
+                    Either::Right(_) => continue,
+                },
+            };
+            match job {
+                Ok(job) => running.push(job.run()),
+                Err(_) => break,
+            }
+        }
+    };
+
+    // Nothing is ever sent, it's only closed
+    select(pin!(run), pin!(stopped.recv())).await;
+}
+
+struct Job {
+    command: String,
+    timeout: Duration,
+    details: Details,
+}
+
+impl Job {
+    async fn run(self) {
+        if let Err(err) = self.try_run().await {
+            eprintln!("error: hook: `{}`: {err}", self.command);
+        }
+    }
+
+    async fn try_run(&self) -> io::Result<()> {
+        let input = serde_json::to_vec(&self.details)?;
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .envs(env(&self.details))
+            .stdin(Stdio::piped())
+            .kill_on_drop(true);
+        for name in sd_notify::LISTEN_ENV {
+            command.env_remove(name);
+        }
+        let mut child = command.spawn()?;
+
+        let mut stdin = child.stdin.take().unwrap();
+        let status = match select(
+            pin!(async {
+                // Hooks don't have to read their input
+                let _ = stdin.write_all(&input).await;
+                drop(stdin);
+                child.status().await
+            }),
+            Timer::after(self.timeout),
+        )
+        .await
+        {
+            Either::Left((status, _)) => Some(status?),
+            Either::Right(_) => None,
+        };
+
+        match status {
+            Some(status) if !status.success() => {
+                eprintln!("notice: hook: `{}` exited with {status}", self.command);
+            }
+            Some(_) => (),
+            None => {
+                eprintln!("notice: hook: `{}` timed out, killing it", self.command);
+                child.kill()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Same fields as the JSON object, eg. `CEC_SYNC_KEY_EVENT=press`
+fn env(details: &Details) -> Vec<(String, String)> {
+    let Ok(Value::Object(fields)) = serde_json::to_value(details) else {
+        unreachable!()
+    };
+
+    fields
+        .into_iter()
+        .map(|(name, value)| {
+            let value = match value {
+                Value::String(value) => value,
+                value => value.to_string(),
+            };
+            (format!("CEC_SYNC_{}", name.to_uppercase()), value)
+        })
+        .collect()
+}
@@ -1,16 +1,44 @@
-pub mod all;
-pub mod dbus;
-pub mod udev;
+//! Backends sync the CEC bus with the rest of the system
+//!
+//! Every CEC [`Event`] is passed to each backend's [`Proxy`], which can
+//! respond with [`Request`]s. Backends also send requests to the service
+//! on their own through their [`Stream`].
+
+#[cfg(feature = "dbus")]
+pub(crate) mod dbus;
+pub(crate) mod hooks;
+pub(crate) mod observer;
+pub(crate) mod registry;
+pub(crate) mod rules;
+#[cfg(feature = "scripting")]
+pub(crate) mod scripting;
+pub(crate) mod signal;
+pub(crate) mod sleep_timer;
+pub(crate) mod standby;
+pub(crate) mod state;
+#[cfg(feature = "udev")]
+pub(crate) mod udev;
+#[cfg(feature = "unix-socket")]
 pub mod unix_socket;
-pub mod wayland;
+pub(crate) mod volume_limit;
+pub(crate) mod watchdog;
+#[cfg(feature = "wayland")]
+pub(crate) mod wayland;
 
 use {
-    crate::macro_command::MacroCommand,
+    crate::{
+        keymap::{Action, KeyEvent},
+        macro_command::MacroCommand,
+        scene::SceneCommand,
+    },
     cec_rs::{CecCommand, CecKeypress, CecLogMessage},
-    futures_util::stream,
-    std::ffi::CString,
+    futures_util::{StreamExt, TryFutureExt, TryStreamExt, stream, try_join},
+    std::{convert::Infallible, ffi::CString},
 };
 
+// The service runs on a single thread, so there's no need for the
+// futures to be Send
+#[allow(async_fn_in_trait)]
 pub trait Backend: Sized {
     type Context;
 
@@ -26,30 +54,222 @@ pub trait Backend: Sized {
 
     async fn new(ctx: Self::Context) -> Result<Self, Self::Error>;
 
+    /// Apply a new context after the config was reloaded
+    ///
+    /// By default, the backend is kept as it is.
+    async fn reload(&mut self, ctx: Self::Context) -> Result<(), Self::Error> {
+        let _ = ctx;
+        Ok(())
+    }
+
     async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error>;
 }
 
+/// A backend that does nothing, standing in for backends that were
+/// compiled out
+impl Backend for () {
+    type Context = ();
+
+    type Error = Infallible;
+
+    type Proxy<'a> = ();
+
+    type Stream<'a> = ();
+
+    async fn new(_ctx: Self::Context) -> Result<Self, Self::Error> {
+        Ok(())
+    }
+
+    async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error> {
+        Ok(((), ()))
+    }
+}
+
+/// A pair of backends, which can be nested to combine any number of them
+impl<A: Backend, B: Backend> Backend for (A, B) {
+    type Context = (A::Context, B::Context);
+
+    type Error = EitherError<A::Error, B::Error>;
+
+    type Proxy<'a>
+        = (A::Proxy<'a>, B::Proxy<'a>)
+    where
+        Self: 'a;
+
+    type Stream<'a>
+        = (A::Stream<'a>, B::Stream<'a>)
+    where
+        Self: 'a;
+
+    async fn new(ctx: Self::Context) -> Result<Self, Self::Error> {
+        try_join!(
+            A::new(ctx.0).map_err(EitherError::Left),
+            B::new(ctx.1).map_err(EitherError::Right)
+        )
+    }
+
+    async fn reload(&mut self, ctx: Self::Context) -> Result<(), Self::Error> {
+        try_join!(
+            self.0.reload(ctx.0).map_err(EitherError::Left),
+            self.1.reload(ctx.1).map_err(EitherError::Right)
+        )?;
+
+        Ok(())
+    }
+
+    async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error> {
+        let ((a_proxy, a_stream), (b_proxy, b_stream)) = try_join!(
+            self.0.split().map_err(EitherError::Left),
+            self.1.split().map_err(EitherError::Right)
+        )?;
+
+        Ok(((a_proxy, b_proxy), (a_stream, b_stream)))
+    }
+}
+
+/// Error of either backend in a pair
+#[derive(thiserror::Error, Debug)]
+pub enum EitherError<A, B> {
+    #[error(transparent)]
+    Left(A),
+    #[error(transparent)]
+    Right(B),
+}
+
+/// An optional backend, only created if it has a context
+impl<B: Backend> Backend for Option<B> {
+    type Context = Option<B::Context>;
+
+    type Error = B::Error;
+
+    type Proxy<'a>
+        = Option<B::Proxy<'a>>
+    where
+        Self: 'a;
+
+    type Stream<'a>
+        = Option<B::Stream<'a>>
+    where
+        Self: 'a;
+
+    async fn new(ctx: Self::Context) -> Result<Self, Self::Error> {
+        Ok(match ctx {
+            Some(ctx) => Some(B::new(ctx).await?),
+            None => None,
+        })
+    }
+
+    /// Create the backend if it was enabled, or drop it if it was disabled
+    async fn reload(&mut self, ctx: Self::Context) -> Result<(), Self::Error> {
+        match (self.as_mut(), ctx) {
+            (Some(backend), Some(ctx)) => backend.reload(ctx).await?,
+            (None, Some(ctx)) => *self = Some(B::new(ctx).await?),
+            (_, None) => *self = None,
+        }
+
+        Ok(())
+    }
+
+    async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error> {
+        Ok(match self {
+            Some(backend) => {
+                let (proxy, stream) = backend.split().await?;
+                (Some(proxy), Some(stream))
+            }
+            None => (None, None),
+        })
+    }
+}
+
+#[allow(async_fn_in_trait)]
 pub trait Proxy {
     type Error;
 
-    async fn event(&mut self, event: &Event) -> Result<(), Self::Error>;
+    /// Handle an event from the CEC bus, returning requests to respond
+    /// to it
+    ///
+    /// The requests are handled in order, after the event was passed
+    /// to every proxy, and before the requests for any later event.
+    async fn event(&mut self, event: &Event) -> Result<Vec<Request>, Self::Error>;
+
+    /// Handle an action a key press was mapped to by the keymap
+    ///
+    /// Unlike events, actions are offered to one proxy at a time, until
+    /// one of them claims it by returning `Some`.
+    async fn action(&mut self, action: &Action) -> Result<Option<Vec<Request>>, Self::Error> {
+        let _ = action;
+        Ok(None)
+    }
 }
 
 #[derive(Clone)]
+#[non_exhaustive]
 pub enum Event {
     KeyPress(CecKeypress),
     Command(CecCommand),
     LogMessage(CecLogMessage),
+    /// A key press interpreted by the keymap, with its repeats, long and
+    /// double presses
+    Key(KeyEvent),
+    /// The connection to the CEC adapter was (re)opened
+    AdapterConnected,
+    /// The connection to the CEC adapter was closed
+    AdapterRemoved,
 }
 
 impl Proxy for () {
-    type Error = ();
+    type Error = Infallible;
 
-    async fn event(&mut self, _event: &Event) -> Result<(), Self::Error> {
-        Ok(())
+    async fn event(&mut self, _event: &Event) -> Result<Vec<Request>, Self::Error> {
+        Ok(Vec::new())
     }
 }
 
+impl<A: Proxy, B: Proxy> Proxy for (A, B) {
+    type Error = EitherError<A::Error, B::Error>;
+
+    async fn event(&mut self, event: &Event) -> Result<Vec<Request>, Self::Error> {
+        let (mut requests, b_requests) = try_join!(
+            self.0.event(event).map_err(EitherError::Left),
+            self.1.event(event).map_err(EitherError::Right)
+        )?;
+
+        requests.extend(b_requests);
+        Ok(requests)
+    }
+
+    async fn action(&mut self, action: &Action) -> Result<Option<Vec<Request>>, Self::Error> {
+        if let Some(requests) = self.0.action(action).await.map_err(EitherError::Left)? {
+            return Ok(Some(requests));
+        }
+
+        self.1.action(action).await.map_err(EitherError::Right)
+    }
+}
+
+impl<P: Proxy> Proxy for Option<P> {
+    type Error = P::Error;
+
+    async fn event(&mut self, event: &Event) -> Result<Vec<Request>, Self::Error> {
+        match self {
+            Some(proxy) => proxy.event(event).await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    async fn action(&mut self, action: &Action) -> Result<Option<Vec<Request>>, Self::Error> {
+        match self {
+            Some(proxy) => proxy.action(action).await,
+            None => Ok(None),
+        }
+    }
+}
+
+/// Requests a backend sends on its own
+///
+/// The stream is only polled again once the service handled its last
+/// request (eg. the CEC command was sent and acknowledged), so a backend
+/// can wait for its requests before going on.
 pub trait Stream {
     type Error;
 
@@ -60,16 +280,56 @@ pub trait Stream {
 }
 
 impl Stream for () {
-    type Error = ();
+    type Error = Infallible;
 
     fn into_stream(self) -> impl futures_util::Stream<Item = Result<Request, Self::Error>> {
         stream::empty()
     }
 }
 
-#[derive(Debug, Clone)]
+impl<A: Stream, B: Stream> Stream for (A, B) {
+    type Error = EitherError<A::Error, B::Error>;
+
+    fn into_stream(self) -> impl futures_util::Stream<Item = Result<Request, Self::Error>> {
+        stream::select(
+            self.0.into_stream().map_err(EitherError::Left),
+            self.1.into_stream().map_err(EitherError::Right),
+        )
+    }
+}
+
+impl<S: Stream> Stream for Option<S> {
+    type Error = S::Error;
+
+    fn into_stream(self) -> impl futures_util::Stream<Item = Result<Request, Self::Error>> {
+        match self {
+            Some(stream) => stream.into_stream().left_stream(),
+            None => stream::empty().right_stream(),
+        }
+    }
+}
+
+#[derive(Clone)]
+#[non_exhaustive]
 pub enum Request {
     ResetDevice(Option<CString>),
-    RemoveDevice(#[expect(dead_code)] CString),
+    RemoveDevice(CString),
     Macro(MacroCommand),
+    /// Send a command on the CEC bus, from this device if the initiator
+    /// is [`CecLogicalAddress::Unknown`](cec_rs::CecLogicalAddress::Unknown)
+    Transmit(CecCommand),
+    /// Run a command with `sh -c`
+    Shell(String),
+    /// Run a sequence from the config by name
+    Sequence(String),
+    /// Save or restore a scene
+    Scene(SceneCommand),
+    /// Offer an action to the proxies, like the ones bound to keys
+    Action(Action),
+    /// Offer actions to the proxies, only if this device is the active
+    /// source
+    IfActiveSource(Vec<Action>),
+    Reload,
+    Shutdown,
+    Watchdog,
 }
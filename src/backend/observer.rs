@@ -0,0 +1,123 @@
+use {
+    crate::{
+        backend::Event,
+        config::HookEvent,
+        keymap::{Key, KeyEvent},
+    },
+    cec_rs::{CecCommand, CecLogicalAddress, CecOpcode},
+    serde::Serialize,
+    std::cell::Cell,
+};
+
+/// Picks the events that hooks and scripts are notified of out of the
+/// events dispatched to the backends
+#[derive(Default)]
+pub struct Observer {
+    // Only notify when these actually change, libcec polls them
+    tv_power: Cell<Option<bool>>,
+    audio_status: Cell<Option<u8>>,
+}
+
+impl Observer {
+    pub fn observe(&self, event: &Event) -> Vec<Details> {
+        match event {
+            Event::Key(key_event) => {
+                let (key, name) = match *key_event {
+                    KeyEvent::Press(key) => (key, "press"),
+                    KeyEvent::LongPress(key) => (key, "long-press"),
+                    KeyEvent::DoublePress(key) => (key, "double-press"),
+                    KeyEvent::Repeat(_) | KeyEvent::Release(_) => return Vec::new(),
+                };
+                vec![Details {
+                    key: Some(Key(key).to_string()),
+                    key_event: Some(name),
+                    ..Details::new(HookEvent::Key)
+                }]
+            }
+            Event::AdapterConnected => vec![Details::new(HookEvent::AdapterConnected)],
+            Event::AdapterRemoved => vec![Details::new(HookEvent::AdapterRemoved)],
+            Event::Command(command) => self.observe_command(command),
+            _ => Vec::new(),
+        }
+    }
+
+    fn observe_command(&self, command: &CecCommand) -> Vec<Details> {
+        let initiator = Details {
+            initiator: Some(command.initiator.repr() as u8),
+            ..Details::new(HookEvent::Standby)
+        };
+        let from_tv = command.initiator == CecLogicalAddress::Tv;
+
+        match command.opcode {
+            CecOpcode::ActiveSource => vec![Details {
+                event: HookEvent::ActiveSource,
+                ..initiator
+            }],
+            CecOpcode::InactiveSource => vec![Details {
+                event: HookEvent::InactiveSource,
+                ..initiator
+            }],
+            CecOpcode::Standby => {
+                let mut details = vec![initiator];
+                if from_tv {
+                    details.extend(self.tv_power(false));
+                }
+                details
+            }
+            CecOpcode::ReportPowerStatus if from_tv => match command.parameters.0.first() {
+                Some(0) => self.tv_power(true).into_iter().collect(),
+                Some(1) => self.tv_power(false).into_iter().collect(),
+                _ => Vec::new(),
+            },
+            CecOpcode::ReportAudioStatus => match command.parameters.0.first() {
+                Some(&status) if self.audio_status.replace(Some(status)) != Some(status) => {
+                    vec![Details {
+                        volume: Some(status & 0x7F),
+                        muted: Some(status & 0x80 != 0),
+                        ..Details::new(HookEvent::AudioStatus)
+                    }]
+                }
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    fn tv_power(&self, on: bool) -> Option<Details> {
+        (self.tv_power.replace(Some(on)) != Some(on)).then(|| {
+            Details::new(match on {
+                true => HookEvent::TvPowerOn,
+                false => HookEvent::TvPowerOff,
+            })
+        })
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct Details {
+    pub event: HookEvent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_event: Option<&'static str>,
+    /// Logical address of the device that sent the command
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initiator: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub muted: Option<bool>,
+}
+
+impl Details {
+    fn new(event: HookEvent) -> Self {
+        Self {
+            event,
+            key: None,
+            key_event: None,
+            initiator: None,
+            volume: None,
+            muted: None,
+        }
+    }
+}
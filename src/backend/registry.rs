@@ -0,0 +1,331 @@
+#[cfg(feature = "mpris")]
+use crate::backend::dbus::mpris;
+#[cfg(feature = "logind")]
+use crate::backend::dbus::systemd_logind;
+#[cfg(feature = "udev")]
+use crate::backend::udev;
+#[cfg(feature = "unix-socket")]
+use crate::backend::unix_socket;
+#[cfg(feature = "wayland")]
+use crate::backend::wayland;
+#[cfg(feature = "scripting")]
+use {
+    crate::backend::scripting,
+    std::{path::PathBuf, time::Duration},
+};
+use {
+    crate::{
+        backend::{
+            self, Event, Request, hooks, rules, signal, sleep_timer, standby, state, volume_limit,
+            watchdog,
+        },
+        config::Config,
+        keymap::Action,
+        state::Store,
+    },
+    futures_util::{
+        FutureExt, StreamExt, TryFutureExt, TryStreamExt,
+        future::{LocalBoxFuture, try_join_all},
+        stream::{LocalBoxStream, select_all},
+    },
+    std::rc::Rc,
+};
+
+/// Backends selected at runtime from the config
+///
+/// Each backend is registered with a name, used in its errors, and a
+/// function picking its context from the shared [`Context`], or `None`
+/// if it's disabled. Backends are created, reloaded and dropped as they
+/// get enabled or disabled.
+pub struct Registry {
+    entries: Vec<Box<dyn Entry>>,
+}
+
+impl Registry {
+    /// Every backend that was compiled in, in the order events are
+    /// dispatched to them and actions are offered to them
+    pub fn builtin() -> Self {
+        let mut registry = Self {
+            entries: Vec::new(),
+        };
+
+        #[cfg(feature = "unix-socket")]
+        registry.register::<unix_socket::Backend>("unix socket", |ctx| {
+            Some(unix_socket::Context {
+                system: ctx.system,
+                access: ctx.config.socket.clone(),
+                state: ctx.state.clone(),
+            })
+        });
+        registry.register::<signal::Backend>("signal", |_| Some(()));
+        registry.register::<watchdog::Backend>("watchdog", |_| Some(()));
+        registry.register::<state::Backend>("state", |ctx| Some(ctx.state.clone()));
+        registry.register::<hooks::Backend>("hooks", |ctx| {
+            (!ctx.config.hooks.hook.is_empty()).then(|| ctx.config.hooks.clone())
+        });
+        registry.register::<rules::Backend>("rules", |ctx| {
+            (!ctx.config.rules.is_empty()).then(|| rules::Context {
+                rules: ctx.config.rules.clone(),
+                state: ctx.state.clone(),
+            })
+        });
+        registry.register::<sleep_timer::Backend>("sleep timer", |ctx| {
+            Some(ctx.config.sleep_timer.clone())
+        });
+        registry.register::<standby::Backend>("standby", |ctx| Some(ctx.config.standby.clone()));
+        registry.register::<volume_limit::Backend>("volume limit", |ctx| {
+            let volume = &ctx.config.volume;
+            (volume.max.is_some() || !volume.quiet_hours.is_empty()).then(|| volume.clone())
+        });
+        #[cfg(feature = "scripting")]
+        registry.register::<scripting::Backend>("scripting", |ctx| {
+            let dir = ctx.config_path.as_deref()?.parent()?.join("scripts");
+            ctx.config.backends.scripting.then(|| scripting::Context {
+                dir,
+                state: ctx.state.clone(),
+                timeout: Duration::from_millis(ctx.config.scripting.timeout_ms),
+                allow_shell: ctx.config.scripting.allow_shell,
+            })
+        });
+        #[cfg(feature = "udev")]
+        registry.register::<udev::Backend>("udev", |ctx| ctx.config.backends.udev.then_some(()));
+        // There's no session bus for a system service
+        #[cfg(feature = "mpris")]
+        registry.register::<mpris::Backend>("mpris", |ctx| {
+            (ctx.config.backends.mpris && !ctx.system).then(|| ctx.state.clone())
+        });
+        #[cfg(feature = "logind")]
+        registry.register::<systemd_logind::Backend>("logind", |ctx| {
+            ctx.config.backends.logind.then(|| systemd_logind::Context {
+                state: ctx.state.clone(),
+                sleep: ctx.config.sleep.clone(),
+                resume: ctx.config.resume.clone(),
+                power_off: ctx.config.power_off.clone(),
+                reboot: ctx.config.reboot.clone(),
+            })
+        });
+        // There's no graphical session for a system service
+        #[cfg(feature = "wayland")]
+        registry.register::<wayland::Backend>("wayland", |ctx| {
+            (ctx.config.backends.wayland && !ctx.system).then_some(())
+        });
+
+        registry
+    }
+
+    pub fn register<B>(&mut self, name: &'static str, context: fn(&Context) -> Option<B::Context>)
+    where
+        B: backend::Backend + 'static,
+        B::Error: std::error::Error + 'static,
+        for<'a> <B::Proxy<'a> as backend::Proxy>::Error: std::error::Error + 'static,
+        for<'a> <B::Stream<'a> as backend::Stream>::Error: std::error::Error + 'static,
+    {
+        self.entries.push(Box::new(Registered::<B> {
+            name,
+            context,
+            backend: None,
+        }));
+    }
+
+    /// Create, reload or drop every backend for a new context
+    async fn apply(&mut self, ctx: &Context) -> Result<(), Error> {
+        try_join_all(self.entries.iter_mut().map(|entry| entry.reload(ctx))).await?;
+        Ok(())
+    }
+}
+
+/// Everything backends pick their context from
+pub struct Context {
+    pub config: Config,
+
+    /// Path of the config file, if there's one
+    #[cfg(feature = "scripting")]
+    pub config_path: Option<PathBuf>,
+
+    pub state: Rc<Store>,
+
+    /// Running as a system service, without a user session
+    #[cfg(any(feature = "unix-socket", feature = "mpris", feature = "wayland"))]
+    pub system: bool,
+}
+
+impl backend::Backend for Registry {
+    type Context = Context;
+    type Error = Error;
+    type Proxy<'a> = Proxy<'a>;
+    type Stream<'a> = Stream<'a>;
+
+    async fn new(ctx: Self::Context) -> Result<Self, Self::Error> {
+        let mut registry = Self::builtin();
+        registry.apply(&ctx).await?;
+        Ok(registry)
+    }
+
+    async fn reload(&mut self, ctx: Self::Context) -> Result<(), Self::Error> {
+        self.apply(&ctx).await
+    }
+
+    async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error> {
+        let (proxies, streams) = try_join_all(self.entries.iter().map(|entry| entry.split()))
+            .await?
+            .into_iter()
+            .unzip();
+
+        Ok((Self::Proxy { proxies }, Self::Stream { streams }))
+    }
+}
+
+pub struct Proxy<'a> {
+    proxies: Vec<Box<dyn DynProxy + 'a>>,
+}
+
+impl backend::Proxy for Proxy<'_> {
+    type Error = Error;
+
+    async fn event(&mut self, event: &Event) -> Result<Vec<Request>, Self::Error> {
+        let requests =
+            try_join_all(self.proxies.iter_mut().map(|proxy| proxy.event(event))).await?;
+        Ok(requests.concat())
+    }
+
+    async fn action(&mut self, action: &Action) -> Result<Option<Vec<Request>>, Self::Error> {
+        for proxy in &mut self.proxies {
+            if let Some(requests) = proxy.action(action).await? {
+                eprintln!("traffic: keymap: {action:?} claimed by {}", proxy.name());
+                return Ok(Some(requests));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+pub struct Stream<'a> {
+    streams: Vec<LocalBoxStream<'a, Result<Request, Error>>>,
+}
+
+impl backend::Stream for Stream<'_> {
+    type Error = Error;
+
+    fn into_stream(self) -> impl futures_util::Stream<Item = Result<Request, Self::Error>> {
+        select_all(self.streams)
+    }
+}
+
+/// Type-erased proxy and stream of a split backend
+type Split<'a> = (
+    Box<dyn DynProxy + 'a>,
+    LocalBoxStream<'a, Result<Request, Error>>,
+);
+
+/// Type-erased [`Registered`] backend
+trait Entry {
+    fn reload<'a>(&'a mut self, ctx: &Context) -> LocalBoxFuture<'a, Result<(), Error>>;
+
+    fn split(&self) -> LocalBoxFuture<'_, Result<Split<'_>, Error>>;
+}
+
+struct Registered<B: backend::Backend> {
+    name: &'static str,
+    context: fn(&Context) -> Option<B::Context>,
+    backend: Option<B>,
+}
+
+impl<B> Entry for Registered<B>
+where
+    B: backend::Backend + 'static,
+    B::Error: std::error::Error + 'static,
+    for<'a> <B::Proxy<'a> as backend::Proxy>::Error: std::error::Error + 'static,
+    for<'a> <B::Stream<'a> as backend::Stream>::Error: std::error::Error + 'static,
+{
+    fn reload<'a>(&'a mut self, ctx: &Context) -> LocalBoxFuture<'a, Result<(), Error>> {
+        let ctx = (self.context)(ctx);
+        let name = self.name;
+        backend::Backend::reload(&mut self.backend, ctx)
+            .map_err(move |err| Error::new(name, err))
+            .boxed_local()
+    }
+
+    fn split(&self) -> LocalBoxFuture<'_, Result<Split<'_>, Error>> {
+        let name = self.name;
+        async move {
+            let (proxy, stream) = backend::Backend::split(&self.backend)
+                .await
+                .map_err(|err| Error::new(name, err))?;
+
+            let proxy: Box<dyn DynProxy + '_> = Box::new(Named { name, proxy });
+            let stream = backend::Stream::into_stream(stream)
+                .map_err(move |err| Error::new(name, err))
+                .boxed_local();
+
+            Ok((proxy, stream))
+        }
+        .boxed_local()
+    }
+}
+
+/// Type-erased [`backend::Proxy`]
+trait DynProxy {
+    fn event<'a>(&'a mut self, event: &'a Event)
+    -> LocalBoxFuture<'a, Result<Vec<Request>, Error>>;
+
+    fn action<'a>(
+        &'a mut self,
+        action: &'a Action,
+    ) -> LocalBoxFuture<'a, Result<Option<Vec<Request>>, Error>>;
+
+    fn name(&self) -> &'static str;
+}
+
+struct Named<P> {
+    name: &'static str,
+    proxy: P,
+}
+
+impl<P> DynProxy for Named<P>
+where
+    P: backend::Proxy,
+    P::Error: std::error::Error + 'static,
+{
+    fn event<'a>(
+        &'a mut self,
+        event: &'a Event,
+    ) -> LocalBoxFuture<'a, Result<Vec<Request>, Error>> {
+        let name = self.name;
+        self.proxy
+            .event(event)
+            .map_err(move |err| Error::new(name, err))
+            .boxed_local()
+    }
+
+    fn action<'a>(
+        &'a mut self,
+        action: &'a Action,
+    ) -> LocalBoxFuture<'a, Result<Option<Vec<Request>>, Error>> {
+        let name = self.name;
+        self.proxy
+            .action(action)
+            .map_err(move |err| Error::new(name, err))
+            .boxed_local()
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("{backend}: {source}")]
+pub struct Error {
+    backend: &'static str,
+    source: Box<dyn std::error::Error>,
+}
+
+impl Error {
+    fn new(backend: &'static str, source: impl std::error::Error + 'static) -> Self {
+        Self {
+            backend,
+            source: Box::new(source),
+        }
+    }
+}
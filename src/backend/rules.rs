@@ -0,0 +1,358 @@
+#[cfg(feature = "logind")]
+use logind_zbus::manager::ManagerProxy;
+use {
+    crate::{
+        backend::{self, Event, Request, observer::Observer},
+        keymap::{Key, KeyEvent},
+        rules::{Facts, Rule, Trigger},
+        state::Store,
+    },
+    async_channel::{Receiver, Sender},
+    async_io::Timer,
+    futures_util::{
+        StreamExt,
+        future::{self, Either, select},
+        stream::{self, LocalBoxStream, unfold},
+    },
+    jiff::Zoned,
+    std::{
+        cell::{Cell, RefCell},
+        collections::BTreeMap,
+        rc::Rc,
+        time::{Duration, Instant},
+    },
+};
+
+// Sleep and resume are only known through logind
+#[cfg(feature = "logind")]
+type Error = zbus::Error;
+#[cfg(not(feature = "logind"))]
+type Error = std::convert::Infallible;
+
+type Triggers<'a> = LocalBoxStream<'a, Result<(Trigger, Option<Key>), Error>>;
+
+/// The monotonic clock stops while the device sleeps and the wall clock
+/// can be changed, so time of day rules check the time at least this
+/// often
+const CLOCK_CHECK: Duration = Duration::from_secs(60);
+
+/// Time of day rules are skipped instead of running this late, eg. when
+/// the device was sleeping at that time
+const MISSED: Duration = Duration::from_secs(5 * 60);
+
+pub struct Context {
+    pub rules: Vec<Rule>,
+    pub state: Rc<Store>,
+}
+
+/// Runs the rules from the config
+pub struct Backend {
+    rules: Vec<Rule>,
+    store: Rc<Store>,
+    observer: Observer,
+    last_key: Cell<Instant>,
+    /// Playback status of the players, to notice when it changes
+    players: RefCell<BTreeMap<String, String>>,
+    tx: Sender<(Trigger, Option<Key>)>,
+    rx: Receiver<(Trigger, Option<Key>)>,
+    #[cfg(feature = "logind")]
+    manager: Option<ManagerProxy<'static>>,
+}
+
+impl backend::Backend for Backend {
+    type Context = Context;
+    type Error = Error;
+    type Proxy<'a> = Proxy<'a>;
+    type Stream<'a> = Stream<'a>;
+
+    async fn new(ctx: Self::Context) -> Result<Self, Self::Error> {
+        // Only connect to the system bus if a rule needs it
+        #[cfg(feature = "logind")]
+        let manager = match ctx
+            .rules
+            .iter()
+            .any(|rule| matches!(rule.on, Trigger::Sleep | Trigger::Resume))
+        {
+            true => Some(ManagerProxy::new(&zbus::Connection::system().await?).await?),
+            false => None,
+        };
+
+        let (tx, rx) = async_channel::unbounded();
+        Ok(Self {
+            players: RefCell::new(Self::players(&ctx.state)),
+            rules: ctx.rules,
+            store: ctx.state,
+            observer: Observer::default(),
+            last_key: Cell::new(Instant::now()),
+            tx,
+            rx,
+            #[cfg(feature = "logind")]
+            manager,
+        })
+    }
+
+    async fn reload(&mut self, ctx: Self::Context) -> Result<(), Self::Error> {
+        *self = Self::new(ctx).await?;
+        Ok(())
+    }
+
+    async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error> {
+        let store = self.store.subscribe().filter_map(|()| {
+            let players = Self::players(&self.store);
+            let changed = *self.players.borrow() != players;
+            self.players.replace(players);
+            future::ready(changed.then_some(Ok((Trigger::PlayerStatus, None))))
+        });
+        let triggers = stream::select_all([
+            self.rx.clone().map(Ok).boxed_local(),
+            store.boxed_local(),
+            self.sleep().await?,
+        ]);
+
+        Ok((
+            Self::Proxy { backend: self },
+            Self::Stream {
+                backend: self,
+                triggers: triggers.boxed_local(),
+            },
+        ))
+    }
+}
+
+impl Backend {
+    /// Sleep and resume triggers, if a rule needs them
+    #[cfg(feature = "logind")]
+    async fn sleep(&self) -> Result<Triggers<'_>, Error> {
+        let Some(manager) = &self.manager else {
+            return Ok(stream::empty().boxed_local());
+        };
+
+        Ok(manager
+            .receive_prepare_for_sleep()
+            .await?
+            .map(|signal| {
+                let trigger = match signal.args()?.start {
+                    true => Trigger::Sleep,
+                    false => Trigger::Resume,
+                };
+                Ok((trigger, None))
+            })
+            .boxed_local())
+    }
+
+    #[cfg(not(feature = "logind"))]
+    async fn sleep(&self) -> Result<Triggers<'_>, Error> {
+        Ok(stream::empty().boxed_local())
+    }
+
+    fn players(store: &Store) -> BTreeMap<String, String> {
+        store
+            .get()
+            .mpris
+            .players
+            .iter()
+            .map(|(name, player)| (name.clone(), player.playback_status.clone()))
+            .collect()
+    }
+
+    /// Check the conditions of a rule, and get its actions if they're met
+    fn run(&self, index: usize, key: Option<Key>) -> Vec<Request> {
+        let rule = &self.rules[index];
+        let state = self.store.get();
+        let unmet = rule.conditions.unmet(&Facts {
+            state: &state,
+            key,
+            idle: Some(self.last_key.get().elapsed()),
+        });
+
+        if !unmet.is_empty() {
+            eprintln!(
+                "traffic: rules: {} skipped, {}",
+                rule.label(index),
+                unmet.join(", ")
+            );
+            return Vec::new();
+        }
+
+        eprintln!("traffic: rules: {} running", rule.label(index));
+        rule.actions.iter().cloned().map(Request::Action).collect()
+    }
+}
+
+pub struct Proxy<'a> {
+    backend: &'a Backend,
+}
+
+impl backend::Proxy for Proxy<'_> {
+    type Error = Error;
+
+    async fn event(&mut self, event: &Event) -> Result<Vec<Request>, Self::Error> {
+        let key = match event {
+            Event::Key(
+                KeyEvent::Press(key) | KeyEvent::LongPress(key) | KeyEvent::DoublePress(key),
+            ) => {
+                self.backend.last_key.set(Instant::now());
+                Some(Key(*key))
+            }
+            _ => None,
+        };
+
+        for details in self.backend.observer.observe(event) {
+            // The receiver lives as long as the backend
+            let _ = self
+                .backend
+                .tx
+                .try_send((Trigger::Event(details.event), key));
+        }
+
+        Ok(Vec::new())
+    }
+}
+
+/// Runs the rules when they're triggered, once their debounce delay or
+/// time of day is reached
+pub struct Stream<'a> {
+    backend: &'a Backend,
+    triggers: Triggers<'a>,
+}
+
+impl<'a> backend::Stream for Stream<'a> {
+    type Error = Error;
+
+    fn into_stream(self) -> impl futures_util::Stream<Item = Result<Request, Self::Error>> {
+        let scheduler = Scheduler::new(self.backend);
+        unfold(
+            (self.triggers, scheduler),
+            |(mut triggers, mut scheduler)| async move {
+                loop {
+                    let next = match scheduler.deadline() {
+                        Some(deadline) => {
+                            match select(triggers.next(), Timer::at(deadline)).await {
+                                Either::Left((next, _)) => Some(next),
+                                Either::Right(_) => None,
+                            }
+                        }
+                        None => Some(triggers.next().await),
+                    };
+
+                    let requests = match next {
+                        Some(Some(Ok((trigger, key)))) => scheduler.trigger(trigger, key),
+                        Some(Some(Err(err))) => return Some((Err(err), (triggers, scheduler))),
+                        Some(None) => return None,
+                        None => scheduler.timeout(),
+                    };
+                    if !requests.is_empty() {
+                        return Some((Ok(requests), (triggers, scheduler)));
+                    }
+                }
+            },
+        )
+        .flat_map(|result| {
+            stream::iter(match result {
+                Ok(requests) => requests.into_iter().map(Ok).collect(),
+                Err(err) => vec![Err(err)],
+            })
+        })
+    }
+}
+
+/// Debounced and time of day rules waiting to run
+struct Scheduler<'a> {
+    backend: &'a Backend,
+    debounced: Vec<Option<(Instant, Option<Key>)>>,
+    times: Vec<Option<Zoned>>,
+}
+
+impl<'a> Scheduler<'a> {
+    fn new(backend: &'a Backend) -> Self {
+        let now = Zoned::now();
+        Self {
+            backend,
+            debounced: vec![None; backend.rules.len()],
+            times: backend
+                .rules
+                .iter()
+                .map(|rule| Self::next_time(rule, &now))
+                .collect(),
+        }
+    }
+
+    fn next_time(rule: &Rule, now: &Zoned) -> Option<Zoned> {
+        let Trigger::Time(time) = rule.on else {
+            return None;
+        };
+
+        match time.next_after(now) {
+            Ok(next) => Some(next),
+            Err(err) => {
+                eprintln!("error: rules: {err}");
+                None
+            }
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        let debounced = self
+            .debounced
+            .iter()
+            .flatten()
+            .map(|(deadline, _)| *deadline);
+
+        let now = Zoned::now();
+        let times = self.times.iter().flatten().map(|time| {
+            let until = Duration::try_from(now.duration_until(time)).unwrap_or_default();
+            Instant::now() + until.min(CLOCK_CHECK)
+        });
+        debounced.chain(times).min()
+    }
+
+    fn trigger(&mut self, trigger: Trigger, key: Option<Key>) -> Vec<Request> {
+        let mut requests = Vec::new();
+        for (index, rule) in self.backend.rules.iter().enumerate() {
+            // Other keys don't trigger (or reset the debounce of) key rules
+            if rule.on != trigger || rule.conditions.key.is_some_and(|other| key != Some(other)) {
+                continue;
+            }
+
+            match rule.debounce_ms {
+                0 => requests.extend(self.backend.run(index, key)),
+                debounce_ms => {
+                    let deadline = Instant::now() + Duration::from_millis(debounce_ms);
+                    self.debounced[index] = Some((deadline, key));
+                }
+            }
+        }
+        requests
+    }
+
+    fn timeout(&mut self) -> Vec<Request> {
+        let now = Instant::now();
+        let wall_now = Zoned::now();
+        let mut requests = Vec::new();
+
+        for index in 0..self.backend.rules.len() {
+            if let Some((deadline, key)) = self.debounced[index]
+                && deadline <= now
+            {
+                self.debounced[index] = None;
+                requests.extend(self.backend.run(index, key));
+            }
+
+            if let Some(time) = self.times[index].take_if(|time| *time <= wall_now) {
+                let rule = &self.backend.rules[index];
+                self.times[index] = Self::next_time(rule, &wall_now);
+
+                let late = Duration::try_from(time.duration_until(&wall_now)).unwrap_or_default();
+                match late < MISSED {
+                    true => requests.extend(self.backend.run(index, None)),
+                    false => eprintln!(
+                        "notice: rules: {} missed at {}, skipping",
+                        rule.label(index),
+                        time.strftime("%H:%M")
+                    ),
+                }
+            }
+        }
+        requests
+    }
+}
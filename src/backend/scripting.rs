@@ -0,0 +1,357 @@
+use {
+    crate::{
+        backend::{self, Event, Request, observer::Observer},
+        keymap::Action,
+        macro_command::MacroCommand,
+        state::Store,
+    },
+    async_channel::{Receiver, Sender},
+    async_io::Timer,
+    futures_util::{
+        StreamExt,
+        future::select,
+        stream::{self, unfold},
+    },
+    rhai::{
+        AST, CallFnOptions, Dynamic, Engine, EvalAltResult, FLOAT, FuncArgs, INT, Map, Scope,
+        module_resolvers::DummyModuleResolver,
+    },
+    std::{
+        cell::{Cell, RefCell},
+        convert::Infallible,
+        fs,
+        io::{self, ErrorKind},
+        path::PathBuf,
+        pin::pin,
+        rc::Rc,
+        time::{Duration, Instant},
+    },
+};
+
+pub struct Context {
+    /// Directory the `*.rhai` scripts are loaded from
+    pub dir: PathBuf,
+
+    pub state: Rc<Store>,
+
+    /// Time after which a script function is stopped
+    pub timeout: Duration,
+
+    /// Whether scripts can run commands with `shell()`
+    pub allow_shell: bool,
+}
+
+/// Runs Rhai scripts on the same events as hooks
+///
+/// Scripts define an `on_event(event)` function, and optionally an
+/// `init()` function called when they're loaded. Both can keep state in
+/// `this`, which is kept until the scripts are reloaded. Scripts can't
+/// import modules or touch the filesystem, can only run commands if the
+/// config allows it, and are stopped if they run for too long.
+pub struct Backend {
+    engine: Engine,
+    scripts: RefCell<Vec<Script>>,
+    outbox: Rc<Outbox>,
+    wakeup: Receiver<()>,
+    observer: Observer,
+}
+
+impl backend::Backend for Backend {
+    type Context = Context;
+    type Error = io::Error;
+    type Proxy<'a> = Proxy<'a>;
+    type Stream<'a> = Stream<'a>;
+
+    async fn new(ctx: Self::Context) -> Result<Self, Self::Error> {
+        let (wakeup_tx, wakeup) = async_channel::bounded(1);
+        let outbox = Rc::new(Outbox {
+            requests: RefCell::default(),
+            timers: RefCell::default(),
+            next_id: Cell::new(0),
+            script: Cell::new(0),
+            timeout: ctx.timeout,
+            deadline: Cell::new(Instant::now()),
+            wakeup: wakeup_tx,
+        });
+
+        let backend = Self {
+            engine: Self::engine(&outbox, ctx.state, ctx.allow_shell),
+            scripts: RefCell::new(Vec::new()),
+            outbox,
+            wakeup,
+            observer: Observer::default(),
+        };
+        backend.load(&ctx.dir)?;
+        Ok(backend)
+    }
+
+    /// Scripts are reloaded from scratch, dropping their state and timers
+    async fn reload(&mut self, ctx: Self::Context) -> Result<(), Self::Error> {
+        *self = Self::new(ctx).await?;
+        Ok(())
+    }
+
+    async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error> {
+        Ok((
+            Self::Proxy { backend: self },
+            Self::Stream { backend: self },
+        ))
+    }
+}
+
+impl Backend {
+    fn engine(outbox: &Rc<Outbox>, state: Rc<Store>, allow_shell: bool) -> Engine {
+        let mut engine = Engine::new();
+
+        engine
+            .set_module_resolver(DummyModuleResolver::new())
+            .set_max_call_levels(32)
+            .set_max_expr_depths(64, 32)
+            .set_max_string_size(1 << 20)
+            .set_max_array_size(1 << 16)
+            .set_max_map_size(1 << 16)
+            .disable_symbol("eval")
+            .on_print(|text| eprintln!("script: {text}"))
+            .on_debug(|text, source, _| {
+                eprintln!("debug: script: {}: {text}", source.unwrap_or(""))
+            });
+
+        let progress = outbox.clone();
+        engine.on_progress(move |_| {
+            (Instant::now() > progress.deadline.get()).then(|| Dynamic::from("timed out"))
+        });
+
+        let requests = outbox.clone();
+        engine.register_fn(
+            "run",
+            move |command: &str| -> Result<(), Box<EvalAltResult>> {
+                let command: MacroCommand = command.parse().map_err(|err| format!("{err}"))?;
+                requests.push(Request::Macro(command));
+                Ok(())
+            },
+        );
+        let requests = outbox.clone();
+        engine.register_fn(
+            "action",
+            move |action: &str| -> Result<(), Box<EvalAltResult>> {
+                let action: Action = action.parse()?;
+                if matches!(action, Action::Shell(_)) && !allow_shell {
+                    return Err("shell actions aren't allowed, see [scripting] allow-shell".into());
+                }
+                requests.push(Request::Action(action));
+                Ok(())
+            },
+        );
+        if allow_shell {
+            let requests = outbox.clone();
+            engine.register_fn("shell", move |command: &str| {
+                requests.push(Request::Shell(command.to_owned()));
+            });
+        }
+        let requests = outbox.clone();
+        engine.register_fn("reload", move || requests.push(Request::Reload));
+
+        engine.register_fn("state", move || rhai::serde::to_dynamic(&*state.get()));
+
+        let timers = outbox.clone();
+        engine.register_fn(
+            "after",
+            move |seconds: FLOAT, function: &str| -> Result<INT, Box<EvalAltResult>> {
+                let delay = Duration::try_from_secs_f64(seconds).map_err(|err| err.to_string())?;
+                Ok(timers.schedule(delay, function))
+            },
+        );
+        let timers = outbox.clone();
+        engine.register_fn("after", move |seconds: INT, function: &str| -> INT {
+            timers.schedule(Duration::from_secs(seconds.max(0) as u64), function)
+        });
+        let timers = outbox.clone();
+        engine.register_fn("cancel", move |id: INT| timers.cancel(id));
+
+        engine
+    }
+
+    /// Compile every script in `dir`, skipping the ones that fail
+    fn load(&self, dir: &PathBuf) -> io::Result<()> {
+        let mut paths = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<_>>>()?,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        paths.retain(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "rhai")
+        });
+        paths.sort();
+
+        let mut scripts = self.scripts.borrow_mut();
+        for path in paths {
+            let name = path.display().to_string();
+            match self.engine.compile_file(path) {
+                Ok(ast) => scripts.push(Script {
+                    name,
+                    ast,
+                    this: Dynamic::from(Map::new()),
+                }),
+                Err(err) => eprintln!("error: script: {name}: {err}"),
+            }
+        }
+
+        for (index, script) in scripts.iter_mut().enumerate() {
+            self.call(index, script, "init", ());
+        }
+        Ok(())
+    }
+
+    /// Call a function of a script if it defines it, only logging errors
+    fn call(&self, index: usize, script: &mut Script, function: &str, args: impl FuncArgs) {
+        if !script.ast.iter_functions().any(|f| f.name == function) {
+            return;
+        }
+
+        self.outbox.script.set(index);
+        self.outbox
+            .deadline
+            .set(Instant::now() + self.outbox.timeout);
+
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut script.this);
+        if let Err(err) = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut Scope::new(),
+            &script.ast,
+            function,
+            args,
+        ) {
+            eprintln!("error: script: {}: {function}: {err}", script.name);
+        }
+    }
+
+    fn fire_timers(&self, now: Instant) -> Vec<Request> {
+        let due: Vec<_> = {
+            let mut timers = self.outbox.timers.borrow_mut();
+            let (due, pending) = timers.drain(..).partition(|timer| timer.at <= now);
+            *timers = pending;
+            due
+        };
+
+        let mut scripts = self.scripts.borrow_mut();
+        for timer in due {
+            if let Some(script) = scripts.get_mut(timer.script) {
+                self.call(timer.script, script, &timer.function, ());
+            }
+        }
+
+        self.outbox.requests.take()
+    }
+}
+
+struct Script {
+    name: String,
+    ast: AST,
+    this: Dynamic,
+}
+
+/// Shared by the functions scripts can call
+struct Outbox {
+    requests: RefCell<Vec<Request>>,
+    timers: RefCell<Vec<ScriptTimer>>,
+    next_id: Cell<INT>,
+    /// Script that's currently running, which owns the timers it sets
+    script: Cell<usize>,
+    timeout: Duration,
+    deadline: Cell<Instant>,
+    wakeup: Sender<()>,
+}
+
+impl Outbox {
+    fn push(&self, request: Request) {
+        self.requests.borrow_mut().push(request);
+    }
+
+    fn schedule(&self, delay: Duration, function: &str) -> INT {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.timers.borrow_mut().push(ScriptTimer {
+            id,
+            at: Instant::now() + delay,
+            script: self.script.get(),
+            function: function.to_owned(),
+        });
+        // Only needs to wake up the stream once for any number of timers
+        let _ = self.wakeup.try_send(());
+        id
+    }
+
+    fn cancel(&self, id: INT) {
+        self.timers.borrow_mut().retain(|timer| timer.id != id);
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.timers.borrow().iter().map(|timer| timer.at).min()
+    }
+}
+
+/// Function of a script to call later, set with `after(seconds, name)`
+struct ScriptTimer {
+    id: INT,
+    at: Instant,
+    script: usize,
+    function: String,
+}
+
+pub struct Proxy<'a> {
+    backend: &'a Backend,
+}
+
+impl backend::Proxy for Proxy<'_> {
+    type Error = Infallible;
+
+    async fn event(&mut self, event: &Event) -> Result<Vec<Request>, Self::Error> {
+        let backend = self.backend;
+        let mut scripts = backend.scripts.borrow_mut();
+
+        for details in backend.observer.observe(event) {
+            // Serializing plain data should never fail
+            let event = rhai::serde::to_dynamic(&details).unwrap();
+            for (index, script) in scripts.iter_mut().enumerate() {
+                backend.call(index, script, "on_event", (event.clone(),));
+            }
+        }
+
+        Ok(backend.outbox.requests.take())
+    }
+}
+
+/// Calls the script functions set with `after()` when they're due
+pub struct Stream<'a> {
+    backend: &'a Backend,
+}
+
+impl backend::Stream for Stream<'_> {
+    type Error = Infallible;
+
+    fn into_stream(self) -> impl futures_util::Stream<Item = Result<Request, Self::Error>> {
+        unfold(self.backend, |backend| async move {
+            loop {
+                match backend.outbox.deadline() {
+                    Some(deadline) => {
+                        select(pin!(backend.wakeup.recv()), Timer::at(deadline)).await;
+                    }
+                    None => {
+                        let _ = backend.wakeup.recv().await;
+                    }
+                }
+
+                let requests = backend.fire_timers(Instant::now());
+                if !requests.is_empty() {
+                    return Some((requests, backend));
+                }
+            }
+        })
+        .flat_map(|requests| stream::iter(requests.into_iter().map(Ok)))
+    }
+}
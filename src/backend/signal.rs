@@ -0,0 +1,49 @@
+use {
+    crate::backend::{self, Request},
+    async_signal::{Signal, Signals},
+    futures_util::{StreamExt, TryStreamExt, stream},
+    std::{cell::RefCell, io},
+};
+
+/// The signals are registered once, so none is lost while the service
+/// reloads
+pub struct Backend {
+    signals: RefCell<Signals>,
+}
+
+impl backend::Backend for Backend {
+    type Context = ();
+    type Error = io::Error;
+    type Proxy<'a> = ();
+    type Stream<'a> = Stream<'a>;
+
+    async fn new(_: Self::Context) -> Result<Self, Self::Error> {
+        Ok(Self {
+            signals: RefCell::new(Signals::new([Signal::Term, Signal::Int, Signal::Hup])?),
+        })
+    }
+
+    async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error> {
+        Ok((Self::Proxy::default(), Self::Stream { backend: self }))
+    }
+}
+
+pub struct Stream<'a> {
+    backend: &'a Backend,
+}
+
+impl backend::Stream for Stream<'_> {
+    type Error = io::Error;
+
+    fn into_stream(self) -> impl futures_util::Stream<Item = Result<Request, Self::Error>> {
+        // Only one stream is polled at a time, the previous one is
+        // dropped before the backend is split again
+        stream::poll_fn(move |cx| self.backend.signals.borrow_mut().poll_next_unpin(cx)).map_ok(
+            |signal| match signal {
+                Signal::Term | Signal::Int => Request::Shutdown,
+                Signal::Hup => Request::Reload,
+                _ => unreachable!(),
+            },
+        )
+    }
+}
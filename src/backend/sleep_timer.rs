@@ -0,0 +1,254 @@
+use {
+    crate::{
+        backend::{self, Event, Request},
+        config,
+        keymap::{Action, LogindAction, MprisAction, SleepTimerAction},
+        macro_command::{MacroCommand, Power},
+    },
+    arrayvec::ArrayVec,
+    async_channel::{Receiver, Sender},
+    async_io::Timer,
+    cec_rs::{CecCommand, CecDatapacket, CecLogicalAddress, CecOpcode},
+    futures_util::{
+        StreamExt,
+        future::select,
+        stream::{self, unfold},
+    },
+    jiff::SignedDuration,
+    std::{
+        cell::{Cell, RefCell},
+        convert::Infallible,
+        pin::pin,
+        time::{Duration, Instant},
+    },
+};
+
+/// Time left from which the OSD is updated every minute
+const COUNTDOWN: Duration = Duration::from_secs(5 * 60);
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// Pauses the players and puts the devices in standby after some time
+///
+/// The timer is started and cancelled with the `sleep-timer` action,
+/// from the CLI or a key, and shows the time left on the TV with
+/// `Set OSD String`.
+pub struct Backend {
+    config: RefCell<config::SleepTimer>,
+    deadline: Cell<Option<Instant>>,
+    /// Next time the OSD is updated
+    osd: Cell<Option<Instant>>,
+    wakeup_tx: Sender<()>,
+    wakeup: Receiver<()>,
+}
+
+impl backend::Backend for Backend {
+    type Context = config::SleepTimer;
+    type Error = Infallible;
+    type Proxy<'a> = Proxy<'a>;
+    type Stream<'a> = Stream<'a>;
+
+    async fn new(config: Self::Context) -> Result<Self, Self::Error> {
+        let (wakeup_tx, wakeup) = async_channel::bounded(1);
+        Ok(Self {
+            config: RefCell::new(config),
+            deadline: Cell::new(None),
+            osd: Cell::new(None),
+            wakeup_tx,
+            wakeup,
+        })
+    }
+
+    /// A running timer keeps running with the new config
+    async fn reload(&mut self, config: Self::Context) -> Result<(), Self::Error> {
+        self.config.replace(config);
+        Ok(())
+    }
+
+    async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error> {
+        Ok((
+            Self::Proxy { backend: self },
+            Self::Stream { backend: self },
+        ))
+    }
+}
+
+impl Backend {
+    fn start(&self, duration: Duration) -> Vec<Request> {
+        let now = Instant::now();
+        self.deadline.set(Some(now + duration));
+        eprintln!(
+            "notice: sleep timer: expiring in {:#}",
+            SignedDuration::try_from(duration).unwrap_or(SignedDuration::MAX)
+        );
+        self.schedule_osd(now);
+        // Only needs to wake up the stream once for any number of changes
+        let _ = self.wakeup_tx.try_send(());
+        self.osd_now(duration)
+    }
+
+    fn cancel(&self) -> Vec<Request> {
+        if self.deadline.take().is_none() {
+            return Vec::new();
+        }
+
+        eprintln!("notice: sleep timer: cancelled");
+        self.osd.set(None);
+        let _ = self.wakeup_tx.try_send(());
+        match self.config.borrow().osd {
+            true => vec![osd("Sleep off")],
+            false => Vec::new(),
+        }
+    }
+
+    fn schedule_osd(&self, now: Instant) {
+        let config = self.config.borrow();
+        let next = self
+            .deadline
+            .get()
+            .filter(|_| config.osd)
+            .and_then(|deadline| {
+                let interval = Duration::from_millis(config.osd_interval_ms);
+                Some(deadline - next_osd(deadline - now, interval)?)
+            });
+        self.osd.set(next);
+    }
+
+    fn osd_now(&self, left: Duration) -> Vec<Request> {
+        match self.config.borrow().osd {
+            true => vec![osd(&format!("Sleep in {}", minutes(left)))],
+            false => Vec::new(),
+        }
+    }
+
+    fn wake(&self, now: Instant) -> Vec<Request> {
+        let Some(deadline) = self.deadline.get() else {
+            return Vec::new();
+        };
+
+        if deadline <= now {
+            self.deadline.set(None);
+            self.osd.set(None);
+            eprintln!("notice: sleep timer: expired");
+
+            let mut requests = vec![
+                Request::Action(Action::Mpris(MprisAction::Pause)),
+                Request::Macro(MacroCommand::Power(Power::Off {
+                    cooperative: true,
+                    tv: false,
+                })),
+            ];
+            if self.config.borrow().suspend {
+                requests.push(Request::Action(Action::Logind(LogindAction::Suspend)));
+            }
+            return requests;
+        }
+
+        if self.osd.get().is_some_and(|osd| osd <= now) {
+            self.schedule_osd(now);
+            return self.osd_now(deadline - now);
+        }
+
+        Vec::new()
+    }
+}
+
+/// Time left at which the OSD is next updated, the last multiple of the
+/// interval (or minute, in the countdown) before the current time left
+fn next_osd(left: Duration, interval: Duration) -> Option<Duration> {
+    let before = left.as_secs().saturating_sub(1);
+    let interval = interval.as_secs().max(MINUTE.as_secs());
+    let on_interval = before / interval * interval;
+    let on_minute = (before / MINUTE.as_secs() * MINUTE.as_secs()).min(COUNTDOWN.as_secs());
+
+    match on_interval.max(on_minute) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+/// Time left rounded to the minute, eg. `45m` or `1h30`, short enough to
+/// fit in an OSD string
+fn minutes(duration: Duration) -> String {
+    let minutes = (duration.as_secs() + 30) / 60;
+    match minutes {
+        0..60 => format!("{minutes}m"),
+        _ => format!("{}h{:02}", minutes / 60, minutes % 60),
+    }
+}
+
+/// `Set OSD String` for the TV, displayed for its default time
+fn osd(text: &str) -> Request {
+    let mut parameters = ArrayVec::new();
+    parameters.push(0x00);
+    // OSD strings are at most 13 ASCII characters
+    parameters.extend(text.bytes().filter(u8::is_ascii).take(13));
+
+    Request::Transmit(CecCommand {
+        // Sent from this device
+        initiator: CecLogicalAddress::Unknown,
+        destination: CecLogicalAddress::Tv,
+        ack: false,
+        eom: true,
+        opcode: CecOpcode::SetOsdString,
+        parameters: CecDatapacket(parameters),
+        opcode_set: true,
+        transmit_timeout: Duration::from_secs(1),
+    })
+}
+
+pub struct Proxy<'a> {
+    backend: &'a Backend,
+}
+
+impl backend::Proxy for Proxy<'_> {
+    type Error = Infallible;
+
+    async fn event(&mut self, _: &Event) -> Result<Vec<Request>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    async fn action(&mut self, action: &Action) -> Result<Option<Vec<Request>>, Self::Error> {
+        let Action::SleepTimer(action) = action else {
+            return Ok(None);
+        };
+
+        Ok(Some(match *action {
+            SleepTimerAction::Start { seconds } => self.backend.start(Duration::from_secs(seconds)),
+            SleepTimerAction::Cancel => self.backend.cancel(),
+        }))
+    }
+}
+
+/// Expires the timer and updates the OSD when they're due
+pub struct Stream<'a> {
+    backend: &'a Backend,
+}
+
+impl backend::Stream for Stream<'_> {
+    type Error = Infallible;
+
+    fn into_stream(self) -> impl futures_util::Stream<Item = Result<Request, Self::Error>> {
+        unfold(self.backend, |backend| async move {
+            loop {
+                let next = [backend.deadline.get(), backend.osd.get()]
+                    .into_iter()
+                    .flatten()
+                    .min();
+                match next {
+                    Some(next) => {
+                        select(pin!(backend.wakeup.recv()), Timer::at(next)).await;
+                    }
+                    None => {
+                        let _ = backend.wakeup.recv().await;
+                    }
+                }
+
+                let requests = backend.wake(Instant::now());
+                if !requests.is_empty() {
+                    return Some((requests, backend));
+                }
+            }
+        })
+        .flat_map(|requests| stream::iter(requests.into_iter().map(Ok)))
+    }
+}
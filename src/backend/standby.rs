@@ -0,0 +1,172 @@
+use {
+    crate::{
+        backend::{self, Event, Request},
+        config,
+        keymap::{Action, KeyEvent},
+    },
+    async_channel::{Receiver, Sender},
+    async_io::Timer,
+    cec_rs::{CecCommand, CecOpcode},
+    futures_util::{
+        StreamExt,
+        future::select,
+        stream::{self, unfold},
+    },
+    std::{
+        cell::Cell,
+        convert::Infallible,
+        pin::pin,
+        time::{Duration, Instant},
+    },
+};
+
+/// Follows other devices to standby, running the actions from the config
+/// when they ask this device to go to standby
+pub struct Backend {
+    config: config::Standby,
+    /// When the actions run, if there's a standby waiting for its grace
+    /// delay
+    deadline: Cell<Option<Instant>>,
+    wakeup_tx: Sender<()>,
+    wakeup: Receiver<()>,
+}
+
+impl backend::Backend for Backend {
+    type Context = config::Standby;
+    type Error = Infallible;
+    type Proxy<'a> = Proxy<'a>;
+    type Stream<'a> = Stream<'a>;
+
+    async fn new(config: Self::Context) -> Result<Self, Self::Error> {
+        let (wakeup_tx, wakeup) = async_channel::bounded(1);
+        Ok(Self {
+            config,
+            deadline: Cell::new(None),
+            wakeup_tx,
+            wakeup,
+        })
+    }
+
+    async fn reload(&mut self, config: Self::Context) -> Result<(), Self::Error> {
+        self.config = config;
+        Ok(())
+    }
+
+    async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error> {
+        Ok((
+            Self::Proxy { backend: self },
+            Self::Stream { backend: self },
+        ))
+    }
+}
+
+impl Backend {
+    /// Run the actions, after the grace delay if there's one
+    fn follow(&self) -> Vec<Request> {
+        match self.config.grace_ms {
+            0 => self.actions(),
+            grace_ms => {
+                eprintln!("notice: standby: following in {grace_ms}ms unless a key is pressed...");
+                let deadline = Instant::now() + Duration::from_millis(grace_ms);
+                self.deadline.set(Some(deadline));
+                let _ = self.wakeup_tx.try_send(());
+                Vec::new()
+            }
+        }
+    }
+
+    fn actions(&self) -> Vec<Request> {
+        let actions = self.config.actions.iter().cloned();
+        actions.map(Request::Action).collect()
+    }
+
+    fn wake(&self, now: Instant) -> Vec<Request> {
+        match self.deadline.get() {
+            Some(deadline) if deadline <= now => {
+                self.deadline.set(None);
+                self.actions()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+pub struct Proxy<'a> {
+    backend: &'a Backend,
+}
+
+impl backend::Proxy for Proxy<'_> {
+    type Error = Infallible;
+
+    async fn event(&mut self, event: &Event) -> Result<Vec<Request>, Self::Error> {
+        let backend = self.backend;
+        match event {
+            Event::Command(CecCommand {
+                opcode: CecOpcode::Standby,
+                initiator,
+                ..
+            }) => {
+                if !backend.config.from.contains(&(initiator.repr() as u8))
+                    || backend.config.actions.is_empty()
+                {
+                    eprintln!("traffic: standby: from {initiator:?}, ignoring...");
+                    return Ok(Vec::new());
+                }
+
+                eprintln!("traffic: standby: from {initiator:?}");
+                // Whether this device is the active source is checked
+                // right away, while it still reflects the time of the
+                // Standby, and not after the grace delay
+                return Ok(match backend.config.if_active_source {
+                    true => vec![Request::IfActiveSource(vec![Action::FollowStandby])],
+                    false => backend.follow(),
+                });
+            }
+            Event::Key(KeyEvent::Press(_)) if backend.deadline.get().is_some() => {
+                backend.deadline.set(None);
+                eprintln!("notice: standby: cancelled by a key press");
+                let _ = backend.wakeup_tx.try_send(());
+            }
+            _ => (),
+        }
+
+        Ok(Vec::new())
+    }
+
+    async fn action(&mut self, action: &Action) -> Result<Option<Vec<Request>>, Self::Error> {
+        Ok(match action {
+            Action::FollowStandby => Some(self.backend.follow()),
+            _ => None,
+        })
+    }
+}
+
+/// Runs the actions once the grace delay is over
+pub struct Stream<'a> {
+    backend: &'a Backend,
+}
+
+impl backend::Stream for Stream<'_> {
+    type Error = Infallible;
+
+    fn into_stream(self) -> impl futures_util::Stream<Item = Result<Request, Self::Error>> {
+        unfold(self.backend, |backend| async move {
+            loop {
+                match backend.deadline.get() {
+                    Some(deadline) => {
+                        select(pin!(backend.wakeup.recv()), Timer::at(deadline)).await;
+                    }
+                    None => {
+                        let _ = backend.wakeup.recv().await;
+                    }
+                }
+
+                let requests = backend.wake(Instant::now());
+                if !requests.is_empty() {
+                    return Some((requests, backend));
+                }
+            }
+        })
+        .flat_map(|requests| stream::iter(requests.into_iter().map(Ok)))
+    }
+}
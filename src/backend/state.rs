@@ -0,0 +1,112 @@
+use {
+    crate::{
+        backend::{self, Event, Request},
+        state::{Audio, Store},
+    },
+    cec_rs::{CecCommand, CecLogicalAddress, CecOpcode},
+    std::{convert::Infallible, rc::Rc},
+};
+
+/// Records the state of the CEC bus, so it isn't lost on restart
+pub struct Backend {
+    store: Rc<Store>,
+}
+
+impl backend::Backend for Backend {
+    type Context = Rc<Store>;
+    type Error = Infallible;
+    type Proxy<'a> = Proxy<'a>;
+    type Stream<'a> = ();
+
+    async fn new(store: Self::Context) -> Result<Self, Self::Error> {
+        Ok(Self { store })
+    }
+
+    async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error> {
+        Ok((Self::Proxy { backend: self }, Self::Stream::default()))
+    }
+}
+
+pub struct Proxy<'a> {
+    backend: &'a Backend,
+}
+
+impl backend::Proxy for Proxy<'_> {
+    type Error = Infallible;
+
+    async fn event(&mut self, event: &Event) -> Result<Vec<Request>, Self::Error> {
+        let Event::Command(command) = event else {
+            return Ok(Vec::new());
+        };
+
+        match command {
+            CecCommand {
+                opcode: CecOpcode::ReportAudioStatus,
+                parameters,
+                ..
+            } => {
+                if let Some(&status) = parameters.0.first() {
+                    self.backend.store.update(|state| {
+                        state.audio = Some(Audio {
+                            volume: status & 0x7F,
+                            muted: status & 0x80 != 0,
+                        })
+                    });
+                }
+            }
+            CecCommand {
+                opcode: CecOpcode::ActiveSource,
+                initiator,
+                parameters,
+                ..
+            } => {
+                let initiator = initiator.repr() as u8;
+                let physical_address = match parameters.0.as_slice() {
+                    &[high, low, ..] => Some(u16::from_be_bytes([high, low])),
+                    _ => None,
+                };
+                self.backend.store.update(|state| {
+                    state.active_source = Some(initiator);
+                    state.active_source_physical_address = physical_address;
+                });
+            }
+            CecCommand {
+                opcode: CecOpcode::ReportPowerStatus,
+                initiator: CecLogicalAddress::Tv,
+                parameters,
+                ..
+            } => {
+                if let Some(&status @ (0 | 1)) = parameters.0.first() {
+                    self.backend
+                        .store
+                        .update(|state| state.tv_power = Some(status == 0));
+                }
+            }
+            CecCommand {
+                opcode: CecOpcode::Standby,
+                initiator: CecLogicalAddress::Tv,
+                ..
+            } => {
+                self.backend
+                    .store
+                    .update(|state| state.tv_power = Some(false));
+            }
+            CecCommand {
+                opcode: CecOpcode::InactiveSource,
+                initiator,
+                ..
+            } => {
+                let initiator = initiator.repr() as u8;
+                self.backend.store.update(|state| {
+                    if state.active_source == Some(initiator) {
+                        state.active_source = None;
+                        state.active_source_physical_address = None;
+                    }
+                });
+            }
+            _ => (),
+        }
+
+        Ok(Vec::new())
+    }
+}
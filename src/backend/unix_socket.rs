@@ -1,98 +1,538 @@
 use {
     crate::{
         backend::{self, Request},
-        macro_command::MacroCommand,
+        config,
+        keymap::{Action, SleepTimerAction},
+        macro_command::{MacroCommand, Permission},
+        scene::SceneCommand,
+        state::Store,
     },
-    async_io::Async,
+    async_io::{Async, Timer},
     async_net::unix::UnixDatagram,
-    futures_util::{StreamExt, ready},
+    futures_util::{
+        StreamExt,
+        future::{self, Either, select},
+        ready,
+    },
+    nix::unistd::{Gid, Group, Uid, User, getgrouplist},
     postcard::experimental::max_size::MaxSize,
+    rustix::{
+        io::{Errno, FdFlags, fcntl_setfd},
+        net::{
+            AddressFamily, RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags, SendFlags,
+            SocketAddrAny, SocketAddrUnix, SocketType, UCred, bind, recvmsg, sendto, sockopt,
+        },
+    },
+    serde::{Deserialize, Serialize},
     std::{
-        env, fs,
-        io::{self},
+        env,
+        ffi::CString,
+        fs::{self, DirBuilder, Permissions},
+        io::{self, IoSliceMut},
+        mem::MaybeUninit,
+        os::{
+            fd::{FromRawFd, OwnedFd, RawFd},
+            unix::fs::{DirBuilderExt, PermissionsExt},
+        },
         path::PathBuf,
-        pin::Pin,
-        sync::Arc,
+        pin::{Pin, pin},
+        rc::Rc,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
         task::Poll,
+        time::Duration,
     },
 };
 
+/// Whether the socket passed by the service manager was taken, by this
+/// backend or an earlier one
+static LISTEN_FD_TAKEN: AtomicBool = AtomicBool::new(false);
+
 pub struct Backend {
+    // None if the socket was inherited through socket activation,
+    // in which case it's owned by the service manager
+    path: Option<PathBuf>,
     socket: UnixDatagram,
+    access: Access,
+    state: Rc<Store>,
 }
 
 impl Backend {
+    /// First file descriptor passed through systemd socket activation
+    /// (SD_LISTEN_FDS_START)
+    const LISTEN_FDS_START: RawFd = 3;
+
+    /// How long to wait for the service to reply to a query
+    const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Largest reply to a query that can be received
+    const MAX_REPLY_SIZE: usize = 64 * 1024;
+
     pub fn path() -> PathBuf {
         let mut socket_path = env::var_os("XDG_RUNTIME_DIR")
             .map(PathBuf::from)
-            .unwrap_or_else(|| env::temp_dir());
+            .unwrap_or_else(env::temp_dir);
 
         socket_path.push("cec-sync");
         socket_path
     }
+
+    /// Socket path used when running as a system service
+    pub fn system_path() -> PathBuf {
+        PathBuf::from("/run/cec-sync/cec-sync")
+    }
+
+    /// Send a message to a running service
+    ///
+    /// A service running in the user's session is preferred over the
+    /// system service.
+    pub async fn send(message: Message) -> Result<(), io::Error> {
+        Self::send_from(&UnixDatagram::unbound()?, message).await
+    }
+
+    /// Get the state of the devices from a running service, in the
+    /// format of the state file
+    pub async fn status() -> Result<String, io::Error> {
+        // Bind to an address picked by the kernel, so the service can reply
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        bind(&socket, &SocketAddrUnix::new_unnamed())?;
+        let socket = UnixDatagram::try_from(socket)?;
+        Self::send_from(&socket, Message::Status).await?;
+
+        let mut buf = vec![0u8; Self::MAX_REPLY_SIZE];
+        let len = {
+            let recv = pin!(socket.recv(&mut buf));
+            match select(recv, Timer::after(Self::REPLY_TIMEOUT)).await {
+                Either::Left((len, _)) => len?,
+                Either::Right(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "no reply, this user may not be allowed to query the service",
+                    ));
+                }
+            }
+        };
+
+        buf.truncate(len);
+        String::from_utf8(buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    async fn send_from(socket: &UnixDatagram, message: Message) -> Result<(), io::Error> {
+        // Serialization should never fail
+        let mut buf = [0u8; Message::POSTCARD_MAX_SIZE];
+        let message = postcard::to_slice(&message, &mut buf).unwrap();
+
+        match socket.send_to(message, Self::path()).await {
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                socket
+                    .send_to(message, Self::system_path())
+                    .await
+                    .map_err(|system_err| match system_err.kind() {
+                        io::ErrorKind::NotFound => err,
+                        _ => system_err,
+                    })?;
+            }
+            result => {
+                result?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Take the listening socket passed by the service manager, if any
+    ///
+    /// See sd_listen_fds(3)
+    fn listen_fd() -> Result<Option<OwnedFd>, Error> {
+        let pid = env::var("LISTEN_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok());
+        let fds = env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|fds| fds.parse::<u32>().ok());
+
+        // The variables are kept, so the backend doesn't take the socket
+        // again if it's created again after a reload
+        if pid != Some(std::process::id()) || LISTEN_FD_TAKEN.swap(true, Ordering::Relaxed) {
+            return Ok(None);
+        }
+
+        match fds {
+            None | Some(0) => return Ok(None),
+            Some(1) => (),
+            Some(fds) => return Err(Error::ListenFds(fds)),
+        }
+
+        // SAFETY: The service manager passes ownership of the file
+        // descriptors starting at SD_LISTEN_FDS_START, and they're
+        // only ever taken once, thanks to LISTEN_FD_TAKEN
+        let fd = unsafe { OwnedFd::from_raw_fd(Self::LISTEN_FDS_START) };
+
+        // Don't leak the socket into child processes
+        fcntl_setfd(&fd, FdFlags::CLOEXEC).map_err(io::Error::from)?;
+
+        if sockopt::socket_domain(&fd).map_err(io::Error::from)? != AddressFamily::UNIX
+            || sockopt::socket_type(&fd).map_err(io::Error::from)? != SocketType::DGRAM
+        {
+            return Err(Error::InvalidListenFd);
+        }
+
+        Ok(Some(fd))
+    }
+}
+
+#[derive(Default)]
+pub struct Context {
+    /// Listen on the system-wide socket
+    pub system: bool,
+
+    pub access: config::Socket,
+
+    /// State reported to status queries
+    pub state: Rc<Store>,
 }
 
 impl backend::Backend for Backend {
-    type Context = ();
+    type Context = Context;
     type Error = Error;
     type Proxy<'a> = ();
-    type Stream<'a> = Stream;
+    type Stream<'a> = Stream<'a>;
+
+    async fn new(ctx: Self::Context) -> Result<Self, Self::Error> {
+        let listen_fd = Self::listen_fd()?;
+        let access = Access::new(&ctx.access)?;
+        let path = match ctx.system {
+            true => {
+                let path = Self::system_path();
+                if let Some(dir) = path.parent() {
+                    DirBuilder::new().recursive(true).mode(0o755).create(dir)?;
+                }
+
+                path
+            }
+            false => Self::path(),
+        };
+
+        let (path, socket) = match listen_fd {
+            Some(fd) => (
+                None,
+                UnixDatagram::try_from(std::os::unix::net::UnixDatagram::from(fd))?,
+            ),
+            None => {
+                // The service holds the instance lock, so no other one
+                // is using the socket and it's safe to remove a stale one
+                let _ = fs::remove_file(&path);
+                let socket = UnixDatagram::bind(&path)?;
+
+                // Anyone can connect to the system socket, commands
+                // are authorized by the sender's credentials instead
+                if ctx.system {
+                    fs::set_permissions(&path, Permissions::from_mode(0o666))?;
+                }
+
+                (Some(path), socket)
+            }
+        };
+
+        // Have the kernel attach the sender's credentials to every datagram
+        let inner: Arc<Async<std::os::unix::net::UnixDatagram>> = socket.clone().into();
+        sockopt::set_socket_passcred(inner.get_ref(), true).map_err(io::Error::from)?;
 
-    async fn new(_: Self::Context) -> Result<Self, Self::Error> {
-        let path = Self::path();
-        let _ = fs::remove_file(&path);
         Ok(Self {
-            socket: UnixDatagram::bind(&path)?,
+            path,
+            socket,
+            access,
+            state: ctx.state,
         })
     }
 
+    /// Only the access lists are reloaded, the socket is kept open
+    async fn reload(&mut self, ctx: Self::Context) -> Result<(), Self::Error> {
+        self.access = Access::new(&ctx.access)?;
+        Ok(())
+    }
+
     async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error> {
         Ok((
             Self::Proxy::default(),
             Self::Stream {
                 socket: self.socket.clone(),
+                access: &self.access,
+                state: &self.state,
             },
         ))
     }
 }
 
-pub struct Stream {
+impl Drop for Backend {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+pub struct Stream<'a> {
     socket: UnixDatagram,
+    access: &'a Access,
+    state: &'a Store,
 }
 
-impl backend::Stream for Stream {
+impl backend::Stream for Stream<'_> {
     type Error = Error;
 
     fn into_stream(self) -> impl futures_util::Stream<Item = Result<Request, Self::Error>> {
-        MacroCommandStream {
-            inner: self.socket.into(),
+        let (access, state) = (self.access, self.state);
+        let inner: Arc<Async<std::os::unix::net::UnixDatagram>> = self.socket.into();
+        DatagramStream {
+            inner: inner.clone(),
+        }
+        .filter_map(move |result| {
+            future::ready(match result {
+                Ok(datagram) => {
+                    access
+                        .authorize(&datagram.bytes, datagram.cred)
+                        .and_then(|message| match message {
+                            // Queries are answered right away, the service
+                            // doesn't need to know about them
+                            Message::Status => {
+                                reply_status(inner.get_ref(), state, datagram.sender);
+                                None
+                            }
+                            message => Some(Ok(message.into())),
+                        })
+                }
+                Err(err) => Some(Err(Error::Io(err))),
+            })
+        })
+    }
+}
+
+fn reply_status(
+    socket: &std::os::unix::net::UnixDatagram,
+    state: &Store,
+    sender: Option<SocketAddrAny>,
+) {
+    let Some(sender) = sender else {
+        eprintln!("notice: unix socket: status query from an unbound socket, ignoring...");
+        return;
+    };
+
+    // Serializing plain data should never fail
+    let reply = toml::to_string(&*state.get()).unwrap();
+    if let Err(err) = sendto(socket, reply.as_bytes(), SendFlags::DONTWAIT, &sender) {
+        eprintln!("notice: unix socket: failed to reply to a status query: {err}, ignoring...");
+    }
+}
+
+/// Users & groups allowed to send commands, resolved from the config
+struct Access {
+    owner: Uid,
+    control: Principals,
+    read_only: Principals,
+}
+
+impl Access {
+    fn new(config: &config::Socket) -> Result<Self, Error> {
+        Ok(Self {
+            owner: Uid::effective(),
+            control: Principals::new(&config.control)?,
+            read_only: Principals::new(&config.read_only)?,
+        })
+    }
+
+    fn authorize(&self, datagram: &[u8], cred: Option<UCred>) -> Option<Message> {
+        let message = match postcard::from_bytes::<Message>(datagram) {
+            Ok(message) => message,
+            Err(err) => {
+                eprintln!("notice: unix socket: invalid command: {err}, ignoring...");
+                return None;
+            }
+        };
+
+        let Some(cred) = cred else {
+            eprintln!("notice: unix socket: missing sender credentials, ignoring {message:?}...");
+            return None;
+        };
+
+        let uid = Uid::from_raw(cred.uid.as_raw());
+        let gid = Gid::from_raw(cred.gid.as_raw());
+        if self
+            .permission(uid, gid)
+            .is_none_or(|permission| permission < message.permission())
+        {
+            eprintln!(
+                "notice: unix socket: uid {uid} (pid {}) isn't allowed to send {message:?}, ignoring...",
+                cred.pid.as_raw_nonzero()
+            );
+
+            return None;
+        }
+
+        Some(message)
+    }
+
+    fn permission(&self, uid: Uid, gid: Gid) -> Option<Permission> {
+        if uid.is_root() || uid == self.owner {
+            return Some(Permission::Control);
+        }
+
+        let groups = User::from_uid(uid)
+            .ok()
+            .flatten()
+            .and_then(|user| CString::new(user.name).ok())
+            .and_then(|name| getgrouplist(&name, gid).ok())
+            .unwrap_or_else(|| vec![gid]);
+
+        if self.control.contains(uid, &groups) {
+            Some(Permission::Control)
+        } else if self.read_only.contains(uid, &groups) {
+            Some(Permission::ReadOnly)
+        } else {
+            None
         }
-        .map(|result| result.map(Request::Macro))
     }
 }
 
-struct MacroCommandStream {
+struct Principals {
+    users: Vec<Uid>,
+    groups: Vec<Gid>,
+}
+
+impl Principals {
+    fn new(config: &config::Access) -> Result<Self, Error> {
+        Ok(Self {
+            users: config
+                .users
+                .iter()
+                .map(|user| match user.parse() {
+                    Ok(uid) => Ok(Uid::from_raw(uid)),
+                    Err(_) => match User::from_name(user) {
+                        Ok(Some(user)) => Ok(user.uid),
+                        Ok(None) => Err(Error::UnknownUser(user.clone())),
+                        Err(err) => Err(Error::Io(err.into())),
+                    },
+                })
+                .collect::<Result<_, _>>()?,
+            groups: config
+                .groups
+                .iter()
+                .map(|group| match group.parse() {
+                    Ok(gid) => Ok(Gid::from_raw(gid)),
+                    Err(_) => match Group::from_name(group) {
+                        Ok(Some(group)) => Ok(group.gid),
+                        Ok(None) => Err(Error::UnknownGroup(group.clone())),
+                        Err(err) => Err(Error::Io(err.into())),
+                    },
+                })
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    fn contains(&self, uid: Uid, groups: &[Gid]) -> bool {
+        self.users.contains(&uid) || groups.iter().any(|gid| self.groups.contains(gid))
+    }
+}
+
+/// Message sent to the service by the CLI
+#[derive(Serialize, Deserialize, MaxSize, Debug, Clone)]
+pub enum Message {
+    Macro(MacroCommand),
+    Reload,
+    /// Run a sequence from the config by name
+    Sequence(Name),
+    SaveScene(Name),
+    RestoreScene(Name),
+    SleepTimer(SleepTimerAction),
+    /// Get the state of the devices, answered by the socket backend
+    Status,
+}
+
+/// Name of a sequence or scene
+pub type Name = heapless::String<64>;
+
+impl Message {
+    fn permission(&self) -> Permission {
+        match self {
+            Message::Macro(command) => command.permission(),
+            Message::Reload => Permission::Control,
+            Message::Sequence(_) => Permission::Control,
+            Message::SaveScene(_) => Permission::Control,
+            Message::RestoreScene(_) => Permission::Control,
+            Message::SleepTimer(_) => Permission::Control,
+            Message::Status => Permission::ReadOnly,
+        }
+    }
+}
+
+impl From<Message> for Request {
+    fn from(value: Message) -> Self {
+        match value {
+            Message::Macro(command) => Request::Macro(command),
+            Message::Reload => Request::Reload,
+            Message::Sequence(name) => Request::Sequence(name.to_string()),
+            Message::SaveScene(name) => Request::Scene(SceneCommand::Save {
+                name: name.to_string(),
+            }),
+            Message::RestoreScene(name) => Request::Scene(SceneCommand::Restore {
+                name: name.to_string(),
+            }),
+            Message::SleepTimer(action) => Request::Action(Action::SleepTimer(action)),
+            Message::Status => unreachable!("status queries are answered by the stream"),
+        }
+    }
+}
+
+struct DatagramStream {
     inner: Arc<Async<std::os::unix::net::UnixDatagram>>,
 }
 
-impl futures_util::Stream for MacroCommandStream {
-    type Item = Result<MacroCommand, Error>;
+/// Datagram received on the socket, with its sender
+struct Datagram {
+    bytes: Vec<u8>,
+    cred: Option<UCred>,
+    /// Address to reply to, if the sender's socket is bound
+    sender: Option<SocketAddrAny>,
+}
+
+impl futures_util::Stream for DatagramStream {
+    type Item = Result<Datagram, io::Error>;
 
     fn poll_next(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
         loop {
-            let mut buf = [0u8; MacroCommand::POSTCARD_MAX_SIZE];
-            match self.inner.get_ref().recv(&mut buf) {
-                Ok(0) => return Poll::Ready(None),
-                Ok(_) => {
-                    return Poll::Ready(Some(
-                        postcard::from_bytes(&buf).map_err(Error::InvalidCommand),
-                    ));
+            let mut buf = [0u8; Message::POSTCARD_MAX_SIZE];
+            let mut space = [MaybeUninit::uninit(); rustix::cmsg_space!(ScmCredentials(1))];
+            let mut control = RecvAncillaryBuffer::new(&mut space);
+            match recvmsg(
+                self.inner.get_ref(),
+                &mut [IoSliceMut::new(&mut buf)],
+                &mut control,
+                RecvFlags::empty(),
+            ) {
+                Ok(msg) => {
+                    let cred = control.drain().find_map(|message| match message {
+                        RecvAncillaryMessage::ScmCredentials(cred) => Some(cred),
+                        _ => None,
+                    });
+
+                    return Poll::Ready(Some(Ok(Datagram {
+                        bytes: buf[..msg.bytes].to_vec(),
+                        cred,
+                        sender: msg.address,
+                    })));
                 }
-                Err(err) if err.kind() == io::ErrorKind::WouldBlock => (),
-                Err(err) => return Poll::Ready(Some(Err(Error::Io(err)))),
+                Err(Errno::WOULDBLOCK) => (),
+                Err(err) => return Poll::Ready(Some(Err(err.into()))),
             };
 
             ready!(self.inner.poll_readable(cx))?;
@@ -104,6 +544,12 @@ impl futures_util::Stream for MacroCommandStream {
 pub enum Error {
     #[error(transparent)]
     Io(#[from] io::Error),
-    #[error("invalid command: {0}")]
-    InvalidCommand(postcard::Error),
+    #[error("expected 1 socket from the service manager, got {0}")]
+    ListenFds(u32),
+    #[error("socket from the service manager isn't a unix datagram socket")]
+    InvalidListenFd,
+    #[error("unknown user: {0}")]
+    UnknownUser(String),
+    #[error("unknown group: {0}")]
+    UnknownGroup(String),
 }
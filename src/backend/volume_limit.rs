@@ -0,0 +1,78 @@
+use {
+    crate::{
+        backend::{self, Event, Request},
+        config,
+        macro_command::{MacroCommand, Volume},
+    },
+    cec_rs::{CecCommand, CecOpcode},
+    std::{cell::Cell, convert::Infallible},
+};
+
+/// Turns the volume back down when it's turned up past the limit from
+/// another remote, eg. the one of the AVR
+pub struct Backend {
+    config: config::Volume,
+    /// Last volume reported by the TV / AVR
+    volume: Cell<Option<u8>>,
+}
+
+impl backend::Backend for Backend {
+    type Context = config::Volume;
+    type Error = Infallible;
+    type Proxy<'a> = Proxy<'a>;
+    type Stream<'a> = ();
+
+    async fn new(config: Self::Context) -> Result<Self, Self::Error> {
+        Ok(Self {
+            config,
+            volume: Cell::new(None),
+        })
+    }
+
+    async fn reload(&mut self, config: Self::Context) -> Result<(), Self::Error> {
+        self.config = config;
+        Ok(())
+    }
+
+    async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error> {
+        Ok((Self::Proxy { backend: self }, Self::Stream::default()))
+    }
+}
+
+pub struct Proxy<'a> {
+    backend: &'a Backend,
+}
+
+impl backend::Proxy for Proxy<'_> {
+    type Error = Infallible;
+
+    async fn event(&mut self, event: &Event) -> Result<Vec<Request>, Self::Error> {
+        let Event::Command(CecCommand {
+            opcode: CecOpcode::ReportAudioStatus,
+            parameters,
+            ..
+        }) = event
+        else {
+            return Ok(Vec::new());
+        };
+        let Some(&status) = parameters.0.first() else {
+            return Ok(Vec::new());
+        };
+
+        // The volume is reported again at every step while it's turned
+        // down, so only the ones turning it up are checked
+        let volume = status & 0x7F;
+        let last = self.backend.volume.replace(Some(volume));
+        match self.backend.config.max() {
+            Some(max_volume) if volume > max_volume && last.is_none_or(|last| volume > last) => {
+                eprintln!(
+                    "notice: volume: {volume} is above the limit of {max_volume}, turning it down..."
+                );
+                Ok(vec![Request::Macro(MacroCommand::Volume(Volume::Set {
+                    volume: max_volume,
+                }))])
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+}
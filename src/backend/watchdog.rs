@@ -0,0 +1,44 @@
+use {
+    crate::{
+        backend::{self, Request},
+        sd_notify,
+    },
+    async_io::Timer,
+    futures_util::{StreamExt, stream},
+    std::convert::Infallible,
+};
+
+pub struct Backend {}
+
+impl backend::Backend for Backend {
+    type Context = ();
+    type Error = Infallible;
+    type Proxy<'a> = ();
+    type Stream<'a> = Stream;
+
+    async fn new(_: Self::Context) -> Result<Self, Self::Error> {
+        Ok(Self {})
+    }
+
+    async fn split<'a>(&'a self) -> Result<(Self::Proxy<'a>, Self::Stream<'a>), Self::Error> {
+        Ok((Self::Proxy::default(), Self::Stream {}))
+    }
+}
+
+pub struct Stream {}
+
+impl backend::Stream for Stream {
+    type Error = Infallible;
+
+    fn into_stream(self) -> impl futures_util::Stream<Item = Result<Request, Self::Error>> {
+        // Pings go through the same queue as every other request, so
+        // if a request gets stuck (eg. in a libcec call), the service
+        // manager will notice and restart the service
+        match sd_notify::watchdog_timeout() {
+            Some(timeout) => Timer::interval(timeout / 2)
+                .map(|_| Ok(Request::Watchdog))
+                .left_stream(),
+            None => stream::pending().right_stream(),
+        }
+    }
+}
@@ -16,9 +16,11 @@ mod gamescope_wayland_client {
 }
 
 use {
-    crate::backend::{self, Event},
+    crate::{
+        backend::{self, Event, Request},
+        keymap::{self, InputAction},
+    },
     async_io::Async,
-    cec_rs::{CecKeypress, CecUserControlCode},
     futures_util::ready,
     gamescope_wayland_client::input_method::{
         __interfaces::GAMESCOPE_INPUT_METHOD_MANAGER_INTERFACE,
@@ -87,49 +89,36 @@ pub struct Proxy {
 impl backend::Proxy for Proxy {
     type Error = Error;
 
-    async fn event(&mut self, event: &Event) -> Result<(), Self::Error> {
+    async fn event(&mut self, _event: &Event) -> Result<Vec<Request>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    /// Claims input actions while gamescope has an input method
+    async fn action(
+        &mut self,
+        action: &keymap::Action,
+    ) -> Result<Option<Vec<Request>>, Self::Error> {
         let state = &self.state;
 
         if let Some(input_method) = &state.input_method
-            && let Event::KeyPress(CecKeypress { keycode, duration }) = event
-            && duration.is_zero()
+            && let keymap::Action::Input(action) = action
         {
-            match keycode {
-                CecUserControlCode::Up => {
-                    input_method.set_action(Action::MoveUp);
-                    input_method.commit(state.serial);
-                    self.event_queue.flush().await?;
-                }
-                CecUserControlCode::Down => {
-                    input_method.set_action(Action::MoveDown);
-                    input_method.commit(state.serial);
-                    self.event_queue.flush().await?;
-                }
-                CecUserControlCode::Left => {
-                    input_method.set_action(Action::MoveLeft);
-                    input_method.commit(state.serial);
-                    self.event_queue.flush().await?;
-                }
-                CecUserControlCode::Right => {
-                    input_method.set_action(Action::MoveRight);
-                    input_method.commit(state.serial);
-                    self.event_queue.flush().await?;
-                }
-                CecUserControlCode::Select => {
-                    input_method.set_action(Action::Submit);
-                    input_method.commit(state.serial);
-                    self.event_queue.flush().await?;
-                }
-                CecUserControlCode::Exit => {
-                    input_method.set_string(String::from("\x1B"));
-                    input_method.commit(state.serial);
-                    self.event_queue.flush().await?;
-                }
-                _ => (),
+            match action {
+                InputAction::MoveUp => input_method.set_action(Action::MoveUp),
+                InputAction::MoveDown => input_method.set_action(Action::MoveDown),
+                InputAction::MoveLeft => input_method.set_action(Action::MoveLeft),
+                InputAction::MoveRight => input_method.set_action(Action::MoveRight),
+                InputAction::Submit => input_method.set_action(Action::Submit),
+                InputAction::DeleteLeft => input_method.set_action(Action::DeleteLeft),
+                InputAction::DeleteRight => input_method.set_action(Action::DeleteRight),
+                InputAction::Text(text) => input_method.set_string(text.clone()),
             }
+            input_method.commit(state.serial);
+            self.event_queue.flush().await?;
+            return Ok(Some(Vec::new()));
         }
 
-        Ok(())
+        Ok(None)
     }
 }
 
@@ -188,7 +177,7 @@ impl Dispatch<WlRegistry, ()> for State {
             if let Some(seat) = state.seat.as_ref()
                 && let Some(input_method_manager) = state.input_method_manager.as_ref()
             {
-                state.input_method = Some(input_method_manager.create_input_method(seat, &qh, ()));
+                state.input_method = Some(input_method_manager.create_input_method(seat, qh, ()));
             }
         }
     }
@@ -227,9 +216,8 @@ impl Dispatch<GamescopeInputMethod, ()> for State {
         _: &Connection,
         _qh: &QueueHandle<State>,
     ) {
-        match event {
-            gamescope_input_method::Event::Done { serial } => state.serial = serial,
-            _ => (),
+        if let gamescope_input_method::Event::Done { serial } = event {
+            state.serial = serial;
         }
     }
 }
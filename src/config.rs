@@ -0,0 +1,519 @@
+use {
+    crate::{
+        keymap::{Action, Binding, Key, LogindAction},
+        macro_command::{Active, MacroCommand, Power},
+        rules::{Rule, TimeOfDay},
+        sequence::Sequence,
+    },
+    cec_rs::CecUserControlCode,
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        env, fs,
+        io::{self, ErrorKind},
+        path::{Path, PathBuf},
+    },
+};
+
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub cec: Cec,
+    pub backends: Backends,
+    pub shutdown: Shutdown,
+    pub sleep: Sleep,
+    pub resume: Resume,
+    pub power_off: PowerOff,
+    pub reboot: Reboot,
+    pub standby: Standby,
+    pub socket: Socket,
+    pub keymap: Keymap,
+    pub hooks: Hooks,
+    pub scripting: Scripting,
+    pub rules: Vec<Rule>,
+    pub sequences: HashMap<String, Sequence>,
+    pub sleep_timer: SleepTimer,
+    pub volume: Volume,
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        let mut config_path = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::home_dir().map(|home| home.join(".config")))?;
+
+        config_path.push("cec-sync");
+        config_path.push("config.toml");
+        Some(config_path)
+    }
+
+    pub fn load(path: Option<&Path>) -> Result<Self, Error> {
+        let (path, explicit) = match path {
+            Some(path) => (path.to_owned(), true),
+            None => match Self::path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents).map_err(|err| Error::Parse(path, err)),
+            // A missing config file is only an error if it was requested explicitly
+            Err(err) if err.kind() == ErrorKind::NotFound && !explicit => Ok(Self::default()),
+            Err(err) => Err(Error::Read(path, err)),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Cec {
+    /// Name reported to other devices on the bus (OSD name)
+    pub device_name: String,
+}
+
+impl Default for Cec {
+    fn default() -> Self {
+        Self {
+            device_name: String::from("cec-sync"),
+        }
+    }
+}
+
+/// Backends to enable
+///
+/// Backends that are always needed by the service (eg. the control
+/// socket) can't be disabled.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Backends {
+    pub logind: bool,
+    pub mpris: bool,
+    pub scripting: bool,
+    pub udev: bool,
+    pub wayland: bool,
+}
+
+impl Default for Backends {
+    fn default() -> Self {
+        Self {
+            logind: true,
+            mpris: true,
+            scripting: true,
+            udev: true,
+            wayland: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Shutdown {
+    /// CEC actions to run (in order) before the service exits
+    pub actions: Vec<ShutdownAction>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            actions: vec![ShutdownAction::InactiveSource],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShutdownAction {
+    /// Power off all devices if this device is the active source
+    Standby,
+
+    /// Unset this device as the active source
+    InactiveSource,
+}
+
+impl From<ShutdownAction> for MacroCommand {
+    fn from(value: ShutdownAction) -> Self {
+        match value {
+            ShutdownAction::Standby => MacroCommand::Power(Power::Off {
+                cooperative: true,
+                tv: false,
+            }),
+            ShutdownAction::InactiveSource => MacroCommand::Active(Active::Unset),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sleep {
+    /// CEC actions to run (in order) before this device goes to sleep
+    pub actions: Vec<SleepAction>,
+}
+
+impl Default for Sleep {
+    fn default() -> Self {
+        Self {
+            actions: vec![SleepAction::Standby],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SleepAction {
+    /// Power off all devices if this device is the active source
+    Standby,
+
+    /// Power off the TV if this device is the active source
+    StandbyTv,
+
+    /// Unset this device as the active source
+    InactiveSource,
+}
+
+impl From<SleepAction> for MacroCommand {
+    fn from(value: SleepAction) -> Self {
+        match value {
+            SleepAction::Standby => MacroCommand::Power(Power::Off {
+                cooperative: true,
+                tv: false,
+            }),
+            SleepAction::StandbyTv => MacroCommand::Power(Power::Off {
+                cooperative: true,
+                tv: true,
+            }),
+            SleepAction::InactiveSource => MacroCommand::Active(Active::Unset),
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Resume {
+    /// CEC actions to run (in order) once this device resumed
+    pub actions: Vec<ResumeAction>,
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResumeAction {
+    PowerOnTv,
+
+    /// Set this device as the active source
+    ActiveSource,
+
+    /// Restore the scene saved before going to sleep
+    RestoreScene,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowerOff {
+    /// CEC actions to run (in order) before this device powers off
+    pub actions: Vec<ShutdownAction>,
+}
+
+impl Default for PowerOff {
+    fn default() -> Self {
+        Self {
+            actions: vec![ShutdownAction::Standby],
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Reboot {
+    /// CEC actions to run (in order) before this device reboots
+    pub actions: Vec<ShutdownAction>,
+}
+
+/// What this device does when another device asks it to go to standby
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Standby {
+    /// Logical addresses of the devices that are followed
+    pub from: Vec<u8>,
+
+    /// Only follow them if this device was the active source when they
+    /// sent it
+    pub if_active_source: bool,
+
+    /// Delay before running the actions, cancelled by any key press
+    pub grace_ms: u64,
+
+    /// Actions run in order, like the ones of rules
+    #[serde(rename = "do")]
+    pub actions: Vec<Action>,
+}
+
+impl Default for Standby {
+    fn default() -> Self {
+        Self {
+            from: vec![0],
+            if_active_source: false,
+            grace_ms: 0,
+            actions: vec![Action::Logind(LogindAction::Suspend)],
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Socket {
+    /// Users & groups allowed to send any command
+    ///
+    /// The user running the service and root are always allowed
+    pub control: Access,
+
+    /// Users & groups only allowed to send queries (eg. status),
+    /// which don't change the state of any device
+    pub read_only: Access,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Access {
+    /// User names or numeric uids
+    pub users: Vec<String>,
+
+    /// Group names or numeric gids
+    pub groups: Vec<String>,
+}
+
+/// Remote key bindings, on top of the default ones
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Keymap {
+    /// Bindings that are always active, unless a mode overrides them
+    pub keys: HashMap<Key, Binding>,
+
+    /// Layers of bindings by name, switched with a `mode` action
+    pub modes: HashMap<String, Mode>,
+
+    /// Bindings for keys held down longer than `timing.long-press-ms`
+    ///
+    /// The short press of these keys only triggers on release.
+    pub long_press: HashMap<Key, Binding>,
+
+    /// Bindings for keys pressed twice within `timing.double-press-ms`
+    ///
+    /// The single press of these keys is delayed until then.
+    pub double_press: HashMap<Key, Binding>,
+
+    /// Keys whose bindings are repeated while they're held down
+    pub repeat: Vec<Key>,
+
+    pub timing: Timing,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self {
+            keys: HashMap::new(),
+            modes: HashMap::new(),
+            long_press: HashMap::new(),
+            double_press: HashMap::new(),
+            repeat: vec![
+                Key(CecUserControlCode::Up),
+                Key(CecUserControlCode::Down),
+                Key(CecUserControlCode::Left),
+                Key(CecUserControlCode::Right),
+            ],
+            timing: Timing::default(),
+        }
+    }
+}
+
+/// Bindings of a mode, overriding the ones of the same key and kind
+/// while it's active
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Mode {
+    /// Bindings for keys pressed, in the mode's table itself
+    #[serde(flatten)]
+    pub keys: HashMap<Key, Binding>,
+
+    pub long_press: HashMap<Key, Binding>,
+
+    pub double_press: HashMap<Key, Binding>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Timing {
+    /// Delay before a held key starts repeating
+    pub repeat_delay_ms: u64,
+
+    /// Delay between repeats of a held key
+    pub repeat_interval_ms: u64,
+
+    /// How long a key has to be held down for a long press
+    pub long_press_ms: u64,
+
+    /// Maximum delay between the two presses of a double press
+    pub double_press_ms: u64,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            repeat_delay_ms: 500,
+            repeat_interval_ms: 100,
+            long_press_ms: 600,
+            double_press_ms: 300,
+        }
+    }
+}
+
+/// Commands run with `sh -c` when something happens on the bus
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Hooks {
+    /// Maximum number of hooks running at once, the others wait for them
+    pub max_concurrent: usize,
+
+    /// Time after which hooks are killed, unless they set their own
+    pub timeout_ms: u64,
+
+    pub hook: Vec<Hook>,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 4,
+            timeout_ms: 10000,
+            hook: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Hook {
+    pub on: HookEvent,
+    pub command: String,
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookEvent {
+    /// A key was pressed, long pressed or double pressed
+    Key,
+
+    /// A device became the active source
+    ActiveSource,
+
+    /// A device stopped being the active source
+    InactiveSource,
+
+    TvPowerOn,
+    TvPowerOff,
+
+    /// A device asked the others to go to standby
+    Standby,
+
+    AdapterConnected,
+    AdapterRemoved,
+
+    /// The volume or mute status of the TV / AVR changed
+    AudioStatus,
+}
+
+/// Rhai scripts loaded from the `scripts` directory next to the config
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Scripting {
+    /// Time after which a script function is stopped
+    pub timeout_ms: u64,
+
+    /// Let scripts run commands with `shell()`
+    pub allow_shell: bool,
+}
+
+impl Default for Scripting {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 100,
+            allow_shell: false,
+        }
+    }
+}
+
+/// What the sleep timer does when it expires, and how it shows the time
+/// left on the TV
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SleepTimer {
+    /// Suspend this device through logind, after pausing the players and
+    /// putting the devices in standby
+    pub suspend: bool,
+
+    /// Show the time left on the TV
+    pub osd: bool,
+
+    /// Time between the OSD updates, which are shown every minute in the
+    /// last 5 minutes
+    pub osd_interval_ms: u64,
+}
+
+impl Default for SleepTimer {
+    fn default() -> Self {
+        Self {
+            suspend: false,
+            osd: true,
+            osd_interval_ms: 15 * 60 * 1000,
+        }
+    }
+}
+
+/// Limits on the volume of the TV / AVR, enforced on volume commands and
+/// when the volume is changed from another remote
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Volume {
+    /// Maximum volume at any time
+    pub max: Option<u8>,
+
+    /// Lower maximum volumes during some hours
+    pub quiet_hours: Vec<QuietHours>,
+}
+
+impl Volume {
+    /// Maximum volume right now, the lowest of the limits that apply
+    pub fn max(&self) -> Option<u8> {
+        let now = TimeOfDay::now();
+        let quiet_hours = self.quiet_hours.iter().filter(|hours| hours.contains(now));
+        quiet_hours.map(|hours| hours.max).chain(self.max).min()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct QuietHours {
+    pub from: TimeOfDay,
+
+    /// End of the quiet hours, which can be on the next day
+    pub to: TimeOfDay,
+
+    pub max: u8,
+}
+
+impl QuietHours {
+    fn contains(&self, time: TimeOfDay) -> bool {
+        match self.from <= self.to {
+            true => self.from <= time && time < self.to,
+            false => self.from <= time || time < self.to,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to read {}: {}", .0.display(), .1)]
+    Read(PathBuf, io::Error),
+    #[error("failed to parse {}: {}", .0.display(), .1)]
+    Parse(PathBuf, toml::de::Error),
+}
@@ -0,0 +1,136 @@
+//! Only one service runs at a time, for each user and for the system
+//!
+//! The running service holds a lock on a file in the runtime directory,
+//! with its pid in it.
+
+use {
+    async_io::Timer,
+    rustix::{
+        io::Errno,
+        process::{Pid, Signal, kill_process},
+    },
+    std::{
+        env,
+        fs::{DirBuilder, File, OpenOptions, TryLockError},
+        io::{self, Read, Seek, Write},
+        os::unix::fs::DirBuilderExt,
+        path::PathBuf,
+        time::{Duration, Instant},
+    },
+};
+
+/// How long to wait for a replaced service to shut down
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Held for as long as the service is running
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    pub fn path(system: bool) -> PathBuf {
+        match system {
+            true => PathBuf::from("/run/cec-sync/cec-sync.lock"),
+            false => {
+                let mut lock_path = env::var_os("XDG_RUNTIME_DIR")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(env::temp_dir);
+
+                lock_path.push("cec-sync.lock");
+                lock_path
+            }
+        }
+    }
+
+    /// Take the lock, or with `replace`, ask the service holding it to
+    /// shut down and wait for it to release it
+    pub async fn acquire(system: bool, replace: bool) -> Result<Self, Error> {
+        let path = Self::path(system);
+        if let Some(dir) = path.parent().filter(|_| system) {
+            DirBuilder::new().recursive(true).mode(0o755).create(dir)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        match file.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                let pid = read_pid(&mut file).await;
+                if !replace {
+                    return Err(Error::AlreadyRunning(pid));
+                }
+
+                // Ask the running service to shut down, and wait for
+                // it to release the lock after it has cleaned up
+                let Some(pid) = pid else {
+                    return Err(Error::UnknownPid);
+                };
+                if let Some(raw) = Pid::from_raw(pid as i32) {
+                    match kill_process(raw, Signal::TERM) {
+                        // It already exited
+                        Ok(()) | Err(Errno::SRCH) => (),
+                        Err(err) => return Err(Error::Io(err.into())),
+                    }
+                }
+
+                let deadline = Instant::now() + REPLACE_TIMEOUT;
+                loop {
+                    match file.try_lock() {
+                        Ok(()) => break,
+                        Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                            Timer::after(Duration::from_millis(100)).await;
+                        }
+                        Err(TryLockError::WouldBlock) => return Err(Error::ReplaceTimeout { pid }),
+                        Err(TryLockError::Error(err)) => return Err(Error::Io(err)),
+                    }
+                }
+            }
+            Err(TryLockError::Error(err)) => return Err(Error::Io(err)),
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self { _file: file })
+    }
+}
+
+/// The pid is written right after the lock is taken, so it may be
+/// missing or partly written for a moment. It's only complete once it
+/// ends with a newline.
+async fn read_pid(file: &mut File) -> Option<u32> {
+    for _ in 0..10 {
+        let mut pid = String::new();
+        file.rewind().ok()?;
+        file.read_to_string(&mut pid).ok()?;
+        if let Some(pid) = pid.strip_suffix('\n')
+            && let Ok(pid) = pid.parse()
+        {
+            return Some(pid);
+        }
+
+        Timer::after(Duration::from_millis(50)).await;
+    }
+
+    None
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to lock the instance: {0}")]
+    Io(#[from] io::Error),
+    #[error("cec-sync service is already running{}, use --replace to replace it", match .0 {
+        Some(pid) => format!(" (pid {pid})"),
+        None => String::new(),
+    })]
+    AlreadyRunning(Option<u32>),
+    #[error("cec-sync service is already running, but its pid is unknown so it can't be replaced")]
+    UnknownPid,
+    #[error("cec-sync service (pid {pid}) didn't shut down within {}s", REPLACE_TIMEOUT.as_secs())]
+    ReplaceTimeout { pid: u32 },
+}
@@ -0,0 +1,688 @@
+//! Mapping of remote keys to actions
+
+use {
+    crate::{config, macro_command::MacroCommand},
+    cec_rs::{CecKeypress, CecUserControlCode},
+    jiff::SignedDuration,
+    postcard::experimental::max_size::MaxSize,
+    serde::{Deserialize, Deserializer, Serialize, de},
+    std::{
+        collections::{HashMap, HashSet},
+        fmt::Display,
+        str::FromStr,
+        time::{Duration, Instant},
+    },
+};
+
+/// Key on the TV remote, named after [`CecUserControlCode`] in
+/// kebab-case (eg. `root-menu`, `f2-red`, `number5`), or one of the
+/// shorter aliases: `0` to `9`, `red`, `green`, `yellow`, `blue` and
+/// `guide`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Key(pub CecUserControlCode);
+
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let code = match name {
+            "0" => CecUserControlCode::Number0,
+            "1" => CecUserControlCode::Number1,
+            "2" => CecUserControlCode::Number2,
+            "3" => CecUserControlCode::Number3,
+            "4" => CecUserControlCode::Number4,
+            "5" => CecUserControlCode::Number5,
+            "6" => CecUserControlCode::Number6,
+            "7" => CecUserControlCode::Number7,
+            "8" => CecUserControlCode::Number8,
+            "9" => CecUserControlCode::Number9,
+            "blue" => CecUserControlCode::F1Blue,
+            "red" => CecUserControlCode::F2Red,
+            "green" => CecUserControlCode::F3Green,
+            "yellow" => CecUserControlCode::F4Yellow,
+            "guide" => CecUserControlCode::ElectronicProgramGuide,
+            _ => (0..=u8::MAX)
+                .filter_map(|repr| CecUserControlCode::from_repr(repr.into()))
+                .find(|code| kebab_case(&format!("{code:?}")) == name)
+                .ok_or_else(|| format!("unknown key {name:?}"))?,
+        };
+
+        Ok(Self(code))
+    }
+}
+
+impl Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&kebab_case(&format!("{:?}", self.0)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        from_str(deserializer)
+    }
+}
+
+fn kebab_case(name: &str) -> String {
+    let mut kebab = String::with_capacity(name.len() + 4);
+    for (i, c) in name.char_indices() {
+        if c.is_ascii_uppercase() && i > 0 {
+            kebab.push('-');
+        }
+        kebab.push(c.to_ascii_lowercase());
+    }
+    kebab
+}
+
+pub(crate) fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+/// One action, or a chain of actions offered to the backends in order
+/// until one of them handles it
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Binding {
+    Action(Action),
+    Chain(Vec<Action>),
+}
+
+impl Binding {
+    fn into_chain(self) -> Vec<Action> {
+        match self {
+            Binding::Action(action) => vec![action],
+            Binding::Chain(actions) => actions,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// Do nothing, eg. to unbind a default key
+    Ignore,
+
+    /// Control all MPRIS media players, if there are any
+    Mpris(MprisAction),
+
+    /// Send input to the gamescope input method, if it's active
+    Input(InputAction),
+
+    /// Ask systemd-logind to change the power state of this device
+    Logind(LogindAction),
+
+    /// Run a CEC command, written like on the command line
+    /// (eg. `"volume up 2"`)
+    Macro(#[serde(deserialize_with = "from_str")] MacroCommand),
+
+    /// Run a command with `sh -c`
+    Shell(String),
+
+    /// Run a sequence from the config by name
+    Sequence(String),
+
+    /// Start or cancel the sleep timer
+    SleepTimer(#[serde(deserialize_with = "from_str")] SleepTimerAction),
+
+    /// Switch to a mode, or back to the default keys if it's already
+    /// active
+    Mode(String),
+
+    /// Follow the Standby the standby backend received, once the service
+    /// checked that this device was the active source then. It's only
+    /// sent by the backend, not written in the config.
+    #[serde(skip)]
+    FollowStandby,
+}
+
+/// Action written on one line, as its kind followed by its value (eg.
+/// `"logind suspend"`, `"macro volume up 2"`, `"input text hello"`), or
+/// `"ignore"`
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        let value = match action.trim().split_once(' ') {
+            None => toml::Value::String(action.trim().to_owned()),
+            Some((kind, value)) => {
+                let value = match value.trim().split_once(' ') {
+                    // The only value that isn't a string
+                    Some(("text", text)) if kind == "input" => {
+                        let text = toml::Value::String(text.to_owned());
+                        toml::Value::Table(toml::Table::from_iter([(String::from("text"), text)]))
+                    }
+                    _ => toml::Value::String(value.trim().to_owned()),
+                };
+                toml::Value::Table(toml::Table::from_iter([(kind.to_owned(), value)]))
+            }
+        };
+
+        value
+            .try_into()
+            .map_err(|err| format!("invalid action {action:?}: {}", err.message()))
+    }
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MprisAction {
+    Play,
+    Pause,
+    PlayPause,
+    Stop,
+    Next,
+    Previous,
+    SeekForward,
+    SeekBackward,
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogindAction {
+    Suspend,
+    Hibernate,
+    SuspendThenHibernate,
+    PowerOff,
+    Reboot,
+    LockSessions,
+}
+
+/// Start the sleep timer, written as a duration (eg. `"45m"`,
+/// `"1h 30m"`), or `"cancel"` it
+#[derive(Serialize, Deserialize, MaxSize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SleepTimerAction {
+    Start { seconds: u64 },
+    Cancel,
+}
+
+impl FromStr for SleepTimerAction {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "cancel" {
+            return Ok(Self::Cancel);
+        }
+
+        let invalid = |err| format!("invalid sleep timer {value:?}: {err}");
+        let duration: SignedDuration = value.parse().map_err(invalid)?;
+        let duration = Duration::try_from(duration).map_err(invalid)?;
+        Ok(Self::Start {
+            seconds: duration.as_secs(),
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InputAction {
+    MoveUp,
+    MoveDown,
+    MoveLeft,
+    MoveRight,
+    Submit,
+    DeleteLeft,
+    DeleteRight,
+    /// Type some text
+    Text(String),
+}
+
+/// Key event produced by the [`Keymap`] from the raw key presses and
+/// releases reported by libcec
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyEvent {
+    Press(CecUserControlCode),
+    /// The key is still held down
+    Repeat(CecUserControlCode),
+    Release(CecUserControlCode),
+    LongPress(CecUserControlCode),
+    DoublePress(CecUserControlCode),
+}
+
+/// Key bindings with the currently active mode and the state of the
+/// keys being pressed
+pub struct Keymap {
+    keys: Layer,
+    modes: HashMap<String, Layer>,
+    mode: Option<String>,
+    repeat: HashSet<CecUserControlCode>,
+    timing: Timing,
+    held: Option<Held>,
+    /// Single press delayed until it can't become a double press
+    pending: Option<(CecUserControlCode, Instant)>,
+}
+
+type Bindings = HashMap<CecUserControlCode, Vec<Action>>;
+
+/// Bindings of the default keys or of a mode
+struct Layer {
+    press: Bindings,
+    long_press: Bindings,
+    double_press: Bindings,
+}
+
+impl Layer {
+    fn new(
+        press: &HashMap<Key, Binding>,
+        long_press: &HashMap<Key, Binding>,
+        double_press: &HashMap<Key, Binding>,
+    ) -> Self {
+        Self {
+            press: Keymap::keys(press),
+            long_press: Keymap::keys(long_press),
+            double_press: Keymap::keys(double_press),
+        }
+    }
+}
+
+struct Timing {
+    repeat_delay: Duration,
+    repeat_interval: Duration,
+    long_press: Duration,
+    double_press: Duration,
+}
+
+/// Key currently held down
+struct Held {
+    key: CecUserControlCode,
+    repeat_at: Option<Instant>,
+    long_press_at: Option<Instant>,
+    /// Whether a press or long press was already produced for it
+    handled: bool,
+}
+
+impl Keymap {
+    pub fn new(config: &config::Keymap) -> Self {
+        let mut keys = Layer::new(&config.keys, &config.long_press, &config.double_press);
+        let mut press = Self::default_keys();
+        press.extend(keys.press);
+        keys.press = press;
+
+        Self {
+            keys,
+            modes: config
+                .modes
+                .iter()
+                .map(|(name, mode)| {
+                    let layer = Layer::new(&mode.keys, &mode.long_press, &mode.double_press);
+                    (name.clone(), layer)
+                })
+                .collect(),
+            mode: None,
+            repeat: config.repeat.iter().map(|key| key.0).collect(),
+            timing: Timing {
+                repeat_delay: Duration::from_millis(config.timing.repeat_delay_ms),
+                repeat_interval: Duration::from_millis(config.timing.repeat_interval_ms),
+                long_press: Duration::from_millis(config.timing.long_press_ms),
+                double_press: Duration::from_millis(config.timing.double_press_ms),
+            },
+            held: None,
+            pending: None,
+        }
+    }
+
+    fn keys(keys: &HashMap<Key, Binding>) -> Bindings {
+        keys.iter()
+            .map(|(key, binding)| (key.0, binding.clone().into_chain()))
+            .collect()
+    }
+
+    /// Bindings used when the config doesn't override them
+    ///
+    /// The input method gets the navigation keys while it's active, and
+    /// some of them control media players otherwise.
+    fn default_keys() -> Bindings {
+        use {CecUserControlCode as Code, InputAction as Input, MprisAction as Mpris};
+
+        HashMap::from([
+            (Code::Play, vec![Action::Mpris(Mpris::Play)]),
+            (Code::Pause, vec![Action::Mpris(Mpris::PlayPause)]),
+            (Code::Stop, vec![Action::Mpris(Mpris::Stop)]),
+            (Code::FastForward, vec![Action::Mpris(Mpris::SeekForward)]),
+            (Code::Rewind, vec![Action::Mpris(Mpris::SeekBackward)]),
+            (Code::Forward, vec![Action::Mpris(Mpris::Next)]),
+            (Code::Backward, vec![Action::Mpris(Mpris::Previous)]),
+            (Code::Up, vec![Action::Input(Input::MoveUp)]),
+            (Code::Down, vec![Action::Input(Input::MoveDown)]),
+            (
+                Code::Left,
+                vec![
+                    Action::Input(Input::MoveLeft),
+                    Action::Mpris(Mpris::SeekBackward),
+                ],
+            ),
+            (
+                Code::Right,
+                vec![
+                    Action::Input(Input::MoveRight),
+                    Action::Mpris(Mpris::SeekForward),
+                ],
+            ),
+            (
+                Code::Select,
+                vec![
+                    Action::Input(Input::Submit),
+                    Action::Mpris(Mpris::PlayPause),
+                ],
+            ),
+            (
+                Code::Exit,
+                vec![Action::Input(Input::Text(String::from("\x1B")))],
+            ),
+        ])
+    }
+
+    /// Chain of actions bound to a key event, from the active mode first
+    pub fn bindings(&self, event: KeyEvent) -> &[Action] {
+        let bindings = match event {
+            KeyEvent::Press(key) | KeyEvent::Repeat(key) => self.lookup(key, |layer| &layer.press),
+            KeyEvent::LongPress(key) => self.lookup(key, |layer| &layer.long_press),
+            KeyEvent::DoublePress(key) => self.lookup(key, |layer| &layer.double_press),
+            KeyEvent::Release(_) => None,
+        };
+
+        bindings.map_or(&[], Vec::as_slice)
+    }
+
+    /// Binding of a key in one kind of bindings of the active mode, or
+    /// of the default keys
+    fn lookup(
+        &self,
+        key: CecUserControlCode,
+        kind: fn(&Layer) -> &Bindings,
+    ) -> Option<&Vec<Action>> {
+        self.mode
+            .as_ref()
+            .and_then(|mode| self.modes.get(mode))
+            .and_then(|layer| kind(layer).get(&key))
+            .or_else(|| kind(&self.keys).get(&key))
+    }
+
+    /// Feed a key press (with a zero duration) or release reported by
+    /// libcec
+    pub fn key_press(&mut self, keypress: CecKeypress, now: Instant) -> Vec<KeyEvent> {
+        let key = keypress.keycode;
+        let mut events = Vec::new();
+
+        if !keypress.duration.is_zero() {
+            if self.held.as_ref().is_some_and(|held| held.key == key) {
+                self.release(now, &mut events);
+            }
+            return events;
+        }
+
+        // libcec reports the key again if the TV repeats it while held
+        if self.held.as_ref().is_some_and(|held| held.key == key) {
+            return events;
+        }
+        if self.held.is_some() {
+            self.release(now, &mut events);
+        }
+
+        if let Some((pending, _)) = self.pending.take() {
+            if pending == key {
+                events.push(KeyEvent::DoublePress(key));
+                self.held = Some(Held {
+                    key,
+                    repeat_at: None,
+                    long_press_at: None,
+                    handled: true,
+                });
+                return events;
+            }
+            events.push(KeyEvent::Press(pending));
+        }
+
+        // The press of keys with long or double press bindings is only
+        // known once they're released
+        let long_press = self.lookup(key, |layer| &layer.long_press).is_some();
+        let deferred = long_press || self.lookup(key, |layer| &layer.double_press).is_some();
+        if !deferred {
+            events.push(KeyEvent::Press(key));
+        }
+        self.held = Some(Held {
+            key,
+            repeat_at: (!deferred && self.repeat.contains(&key))
+                .then(|| now + self.timing.repeat_delay),
+            long_press_at: long_press.then(|| now + self.timing.long_press),
+            handled: !deferred,
+        });
+
+        events
+    }
+
+    fn release(&mut self, now: Instant, events: &mut Vec<KeyEvent>) {
+        let Some(held) = self.held.take() else {
+            return;
+        };
+
+        if !held.handled {
+            match self.lookup(held.key, |layer| &layer.double_press).is_some() {
+                true => self.pending = Some((held.key, now + self.timing.double_press)),
+                false => events.push(KeyEvent::Press(held.key)),
+            }
+        }
+        events.push(KeyEvent::Release(held.key));
+    }
+
+    /// Next time [`Keymap::timeout`] has to be called
+    pub fn deadline(&self) -> Option<Instant> {
+        let held = self.held.as_ref();
+        [
+            self.pending.map(|(_, deadline)| deadline),
+            held.and_then(|held| held.repeat_at),
+            held.and_then(|held| held.long_press_at),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Produce the events whose deadline passed
+    pub fn timeout(&mut self, now: Instant) -> Vec<KeyEvent> {
+        let mut events = Vec::new();
+
+        if let Some((key, deadline)) = self.pending
+            && deadline <= now
+        {
+            self.pending = None;
+            events.push(KeyEvent::Press(key));
+        }
+
+        if let Some(held) = &mut self.held {
+            if held.long_press_at.is_some_and(|deadline| deadline <= now) {
+                held.long_press_at = None;
+                held.handled = true;
+                events.push(KeyEvent::LongPress(held.key));
+            }
+            if held.repeat_at.is_some_and(|deadline| deadline <= now) {
+                held.repeat_at = Some(now + self.timing.repeat_interval);
+                events.push(KeyEvent::Repeat(held.key));
+            }
+        }
+
+        events
+    }
+
+    /// Switch to a mode, or back to the default keys if it's already
+    /// active
+    pub fn switch_mode(&mut self, mode: &str) {
+        self.mode = match self.mode.as_deref() == Some(mode) {
+            true => None,
+            false => Some(mode.to_owned()),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        CecUserControlCode::{Exit, Select, Up},
+    };
+
+    const CONFIG: &str = r#"
+        [long-press]
+        select = { shell = "menu" }
+
+        [double-press]
+        exit = { shell = "home" }
+
+        [modes.media]
+        up = { mpris = "next" }
+
+        [modes.media.long-press]
+        up = { mpris = "stop" }
+
+        [modes.media.double-press]
+        select = { mpris = "play" }
+    "#;
+
+    fn keymap() -> Keymap {
+        Keymap::new(&toml::from_str(CONFIG).unwrap())
+    }
+
+    fn press(key: CecUserControlCode) -> CecKeypress {
+        CecKeypress {
+            keycode: key,
+            duration: Duration::ZERO,
+        }
+    }
+
+    fn release(key: CecUserControlCode) -> CecKeypress {
+        CecKeypress {
+            keycode: key,
+            duration: Duration::from_millis(1),
+        }
+    }
+
+    fn after(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn press_and_repeat() {
+        let mut keymap = keymap();
+        let start = Instant::now();
+
+        assert_eq!(keymap.key_press(press(Up), start), [KeyEvent::Press(Up)]);
+        // libcec reports the key again while the TV repeats it
+        assert_eq!(keymap.key_press(press(Up), after(start, 200)), []);
+        assert_eq!(keymap.deadline(), Some(after(start, 500)));
+        assert_eq!(keymap.timeout(after(start, 499)), []);
+        assert_eq!(keymap.timeout(after(start, 500)), [KeyEvent::Repeat(Up)]);
+        assert_eq!(keymap.deadline(), Some(after(start, 600)));
+        assert_eq!(keymap.timeout(after(start, 600)), [KeyEvent::Repeat(Up)]);
+        assert_eq!(
+            keymap.key_press(release(Up), after(start, 650)),
+            [KeyEvent::Release(Up)]
+        );
+        assert_eq!(keymap.deadline(), None);
+    }
+
+    #[test]
+    fn long_press() {
+        let mut keymap = keymap();
+        let start = Instant::now();
+
+        assert_eq!(keymap.key_press(press(Select), start), []);
+        assert_eq!(keymap.deadline(), Some(after(start, 600)));
+        assert_eq!(
+            keymap.timeout(after(start, 600)),
+            [KeyEvent::LongPress(Select)]
+        );
+        assert_eq!(
+            keymap.key_press(release(Select), after(start, 900)),
+            [KeyEvent::Release(Select)]
+        );
+    }
+
+    #[test]
+    fn short_press_of_long_press_key() {
+        let mut keymap = keymap();
+        let start = Instant::now();
+
+        assert_eq!(keymap.key_press(press(Select), start), []);
+        assert_eq!(
+            keymap.key_press(release(Select), after(start, 100)),
+            [KeyEvent::Press(Select), KeyEvent::Release(Select)]
+        );
+        assert_eq!(keymap.deadline(), None);
+    }
+
+    #[test]
+    fn double_press() {
+        let mut keymap = keymap();
+        let start = Instant::now();
+
+        assert_eq!(keymap.key_press(press(Exit), start), []);
+        assert_eq!(
+            keymap.key_press(release(Exit), after(start, 100)),
+            [KeyEvent::Release(Exit)]
+        );
+        assert_eq!(keymap.deadline(), Some(after(start, 400)));
+        assert_eq!(
+            keymap.key_press(press(Exit), after(start, 200)),
+            [KeyEvent::DoublePress(Exit)]
+        );
+        assert_eq!(
+            keymap.key_press(release(Exit), after(start, 300)),
+            [KeyEvent::Release(Exit)]
+        );
+        assert_eq!(keymap.deadline(), None);
+    }
+
+    #[test]
+    fn single_press_of_double_press_key() {
+        let mut keymap = keymap();
+        let start = Instant::now();
+
+        keymap.key_press(press(Exit), start);
+        keymap.key_press(release(Exit), after(start, 100));
+        assert_eq!(keymap.timeout(after(start, 399)), []);
+        assert_eq!(keymap.timeout(after(start, 400)), [KeyEvent::Press(Exit)]);
+        assert_eq!(keymap.deadline(), None);
+    }
+
+    #[test]
+    fn mode_bindings() {
+        let mut keymap = keymap();
+        let start = Instant::now();
+
+        keymap.switch_mode("media");
+        assert_eq!(
+            keymap.bindings(KeyEvent::Press(Up)),
+            [Action::Mpris(MprisAction::Next)]
+        );
+
+        // Up has a long press binding in the mode, so it's deferred
+        assert_eq!(keymap.key_press(press(Up), start), []);
+        assert_eq!(keymap.timeout(after(start, 600)), [KeyEvent::LongPress(Up)]);
+        assert_eq!(
+            keymap.bindings(KeyEvent::LongPress(Up)),
+            [Action::Mpris(MprisAction::Stop)]
+        );
+        keymap.key_press(release(Up), after(start, 700));
+
+        // The mode's double press binding comes before the default long
+        // press one of the same key
+        assert_eq!(
+            keymap.bindings(KeyEvent::DoublePress(Select)),
+            [Action::Mpris(MprisAction::Play)]
+        );
+        assert_eq!(
+            keymap.bindings(KeyEvent::LongPress(Select)),
+            [Action::Shell(String::from("menu"))]
+        );
+
+        keymap.switch_mode("media");
+        assert_eq!(keymap.bindings(KeyEvent::LongPress(Up)), []);
+        assert_eq!(keymap.key_press(press(Up), start), [KeyEvent::Press(Up)]);
+    }
+}
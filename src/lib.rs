@@ -0,0 +1,24 @@
+//! Sync the CEC bus with your Linux device
+//!
+//! The [`Service`] connects to the CEC adapter and runs the built-in
+//! backends. Embedders can add their own [`Backend`]s through the
+//! [`Builder`].
+
+pub mod backend;
+pub mod config;
+mod instance;
+pub mod keymap;
+pub mod macro_command;
+pub mod rules;
+pub mod scene;
+mod sd_notify;
+pub mod sequence;
+mod service;
+pub mod state;
+
+pub use {
+    backend::{Backend, Event, Proxy, Request, Stream},
+    macro_command::MacroCommand,
+    scene::SceneCommand,
+    service::{Builder, CecError, Error, Service, connect},
+};
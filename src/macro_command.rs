@@ -1,17 +1,17 @@
 use {
-    crate::CecError,
+    crate::service::CecError,
     blocking::unblock,
     cec_rs::{
         CecConnection, CecDeckInfo, CecDeviceType, CecLogicalAddress, CecPowerStatus,
         CecUserControlCode, KnownAndRegisteredCecLogicalAddress, TryFromCecAudioStatusError,
     },
-    clap::Subcommand,
+    clap::{Parser, Subcommand},
     postcard::experimental::max_size::MaxSize,
     serde::{Deserialize, Serialize},
-    std::{future::Future, sync::Arc},
+    std::{future::Future, str::FromStr, sync::Arc},
 };
 
-#[derive(Subcommand, Serialize, Deserialize, MaxSize, Debug, Copy, Clone)]
+#[derive(Subcommand, Serialize, Deserialize, MaxSize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum MacroCommand {
     #[command(subcommand, about = "Change active source device")]
     Active(Active),
@@ -32,7 +32,7 @@ pub enum MacroCommand {
     DeckInfo(DeckInfo),
 }
 
-#[derive(Subcommand, Serialize, Deserialize, MaxSize, Debug, Copy, Clone)]
+#[derive(Subcommand, Serialize, Deserialize, MaxSize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Active {
     #[command(about = "Set this device as the active source")]
     Set {
@@ -44,19 +44,25 @@ pub enum Active {
     Unset,
 }
 
-#[derive(Subcommand, Serialize, Deserialize, MaxSize, Debug, Copy, Clone)]
+#[derive(Subcommand, Serialize, Deserialize, MaxSize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Power {
     #[command(about = "Power on all devices")]
-    On,
+    On {
+        #[arg(long, help = "Only the TV")]
+        tv: bool,
+    },
 
     #[command(about = "Power off all devices")]
     Off {
         #[arg(short, long, help = "Only if this device is the active source")]
         cooperative: bool,
+
+        #[arg(long, help = "Only the TV")]
+        tv: bool,
     },
 }
 
-#[derive(Subcommand, Serialize, Deserialize, MaxSize, Debug, Copy, Clone)]
+#[derive(Subcommand, Serialize, Deserialize, MaxSize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Volume {
     #[command(about = "Increase volume")]
     Up {
@@ -74,7 +80,7 @@ pub enum Volume {
     Set { volume: u8 },
 }
 
-#[derive(Subcommand, Serialize, Deserialize, MaxSize, Debug, Copy, Clone)]
+#[derive(Subcommand, Serialize, Deserialize, MaxSize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Mute {
     #[command(about = "Toggle TV / AVR mute status [default]")]
     Toggle,
@@ -87,20 +93,26 @@ pub enum Mute {
 }
 
 #[derive(
-    Subcommand, Serialize, Deserialize, MaxSize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord,
+    Subcommand,
+    Serialize,
+    Deserialize,
+    MaxSize,
+    Debug,
+    Default,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
 )]
 pub enum DeckInfo {
     Play,
     Still,
+    #[default]
     Stop,
 }
 
-impl Default for DeckInfo {
-    fn default() -> Self {
-        DeckInfo::Stop
-    }
-}
-
 impl From<DeckInfo> for CecDeckInfo {
     fn from(value: DeckInfo) -> Self {
         match value {
@@ -111,22 +123,66 @@ impl From<DeckInfo> for CecDeckInfo {
     }
 }
 
+/// Parse a command written like on the command line, eg. `"power off -c"`
+impl FromStr for MacroCommand {
+    type Err = clap::Error;
+
+    fn from_str(command: &str) -> Result<Self, Self::Err> {
+        #[derive(Parser)]
+        #[command(no_binary_name = true)]
+        struct Args {
+            #[command(subcommand)]
+            command: MacroCommand,
+        }
+
+        Args::try_parse_from(command.split_whitespace()).map(|args| args.command)
+    }
+}
+
+/// Permission required to send a command to the service
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    ReadOnly,
+    Control,
+}
+
 impl MacroCommand {
-    pub fn run(self, cec: Arc<CecConnection>) -> impl Future<Output = Result<(), CecError>> {
-        unblock(move || self.run_sync(&cec))
+    pub fn permission(&self) -> Permission {
+        match self {
+            MacroCommand::Active(_) => Permission::Control,
+            MacroCommand::Power(_) => Permission::Control,
+            MacroCommand::Volume(_) => Permission::Control,
+            MacroCommand::Mute { .. } => Permission::Control,
+            MacroCommand::DeckInfo(_) => Permission::Control,
+        }
+    }
+
+    /// Run the command, without turning the volume up past `max_volume`
+    pub fn run(
+        self,
+        cec: Arc<CecConnection>,
+        max_volume: Option<u8>,
+    ) -> impl Future<Output = Result<(), CecError>> {
+        unblock(move || self.run_sync(&cec, max_volume))
     }
 
-    fn run_sync(self, cec: &CecConnection) -> Result<(), CecError> {
+    fn run_sync(self, cec: &CecConnection, max_volume: Option<u8>) -> Result<(), CecError> {
         match self {
             MacroCommand::Active(Active::Set { cooperative: false }) => active_set(cec),
             MacroCommand::Active(Active::Set { cooperative: true }) => active_set_cooperative(cec),
             MacroCommand::Active(Active::Unset) => active_unset(cec),
-            MacroCommand::Power(Power::On) => power_on(cec),
-            MacroCommand::Power(Power::Off { cooperative: false }) => power_off(cec),
-            MacroCommand::Power(Power::Off { cooperative: true }) => power_off_cooperative(cec),
-            MacroCommand::Volume(Volume::Up { steps }) => volume_up(cec, steps),
+            MacroCommand::Power(Power::On { tv }) => power_on(cec, devices(tv)),
+            MacroCommand::Power(Power::Off {
+                cooperative: false,
+                tv,
+            }) => power_off(cec, devices(tv)),
+            MacroCommand::Power(Power::Off {
+                cooperative: true,
+                tv,
+            }) => power_off_cooperative(cec, devices(tv)),
+            MacroCommand::Volume(Volume::Up { steps }) => volume_up(cec, steps, max_volume),
             MacroCommand::Volume(Volume::Down { steps }) => volume_down(cec, steps),
-            MacroCommand::Volume(Volume::Set { volume }) => volume_set(cec, volume),
+            MacroCommand::Volume(Volume::Set { volume }) => volume_set(cec, volume, max_volume),
             MacroCommand::Mute {
                 command: None | Some(Mute::Toggle),
             } => mute_toggle(cec),
@@ -162,35 +218,61 @@ fn active_unset(cec: &CecConnection) -> Result<(), CecError> {
     Ok(())
 }
 
-fn power_on(cec: &CecConnection) -> Result<(), CecError> {
-    cec.send_power_on_devices(CecLogicalAddress::Unregistered)?;
+/// Address of the TV, or the broadcast address for all devices
+fn devices(tv: bool) -> CecLogicalAddress {
+    match tv {
+        true => CecLogicalAddress::Tv,
+        false => CecLogicalAddress::Unregistered,
+    }
+}
+
+fn power_on(cec: &CecConnection, devices: CecLogicalAddress) -> Result<(), CecError> {
+    cec.send_power_on_devices(devices)?;
+    Ok(())
+}
+
+fn power_off(cec: &CecConnection, devices: CecLogicalAddress) -> Result<(), CecError> {
+    cec.send_standby_devices(devices)?;
     Ok(())
 }
 
-fn power_off(cec: &CecConnection) -> Result<(), CecError> {
-    cec.send_standby_devices(CecLogicalAddress::Unregistered)?;
+fn power_off_cooperative(cec: &CecConnection, devices: CecLogicalAddress) -> Result<(), CecError> {
+    if is_active_source(cec) {
+        power_off(cec, devices)?
+    }
+
     Ok(())
 }
 
-fn power_off_cooperative(cec: &CecConnection) -> Result<(), CecError> {
+pub(crate) fn is_active_source(cec: &CecConnection) -> bool {
     let Some(active_source) = KnownAndRegisteredCecLogicalAddress::new(cec.get_active_source())
     else {
-        return Ok(());
+        return false;
     };
 
     // This would only fail if there was a bug in cec-rs or libcec
     let my_addresses = cec.get_logical_addresses().unwrap().addresses;
-    if my_addresses.contains(&active_source) {
-        power_off(cec)?
-    }
-
-    Ok(())
+    my_addresses.contains(&active_source)
 }
 
-fn volume_up(cec: &CecConnection, steps: u8) -> Result<(), CecError> {
+fn volume_up(cec: &CecConnection, steps: u8, max_volume: Option<u8>) -> Result<(), CecError> {
+    // The volume is only known if the TV / AVR reports it, otherwise the
+    // limit is enforced once it does
+    let mut volume = match max_volume {
+        Some(_) => cec.audio_get_status().ok().map(|status| status.volume()),
+        None => None,
+    };
+
     for _ in 0..steps {
+        if let Some((volume, max_volume)) = volume.zip(max_volume)
+            && volume >= max_volume
+        {
+            eprintln!("notice: volume: at the limit of {max_volume}, not turning it up...");
+            break;
+        }
+
         match cec.volume_up(true) {
-            Ok(_) => (),
+            Ok(status) => volume = Some(status.volume()),
             Err(TryFromCecAudioStatusError::Unknown) => (),
             Err(err) => return Err(CecError::AudioStatus(err)),
         }
@@ -211,13 +293,21 @@ fn volume_down(cec: &CecConnection, steps: u8) -> Result<(), CecError> {
     Ok(())
 }
 
-fn volume_set(cec: &CecConnection, volume: u8) -> Result<(), CecError> {
+fn volume_set(cec: &CecConnection, volume: u8, max_volume: Option<u8>) -> Result<(), CecError> {
+    let volume = match max_volume {
+        Some(max_volume) if volume > max_volume => {
+            eprintln!("notice: volume: {volume} is above the limit, setting it to {max_volume}...");
+            max_volume
+        }
+        _ => volume,
+    };
+
     let status = cec.audio_get_status()?;
     let steps = volume as i8 - status.volume() as i8;
     if steps >= 0 {
-        volume_up(cec, steps as u8)?;
+        volume_up(cec, steps as u8, max_volume)?;
     } else {
-        volume_down(cec, steps as u8)?;
+        volume_down(cec, steps.unsigned_abs())?;
     }
 
     Ok(())
@@ -254,7 +344,7 @@ fn mute_on(cec: &CecConnection) -> Result<(), CecError> {
 }
 
 fn mute_off(cec: &CecConnection) -> Result<(), CecError> {
-    match cec.audio_mute() {
+    match cec.audio_unmute() {
         Ok(_) => (),
         Err(TryFromCecAudioStatusError::Unknown) => {
             cec.send_keypress(
@@ -1,30 +1,30 @@
-mod backend;
-mod macro_command;
-
 use {
-    async_channel::Sender,
-    async_executor::LocalExecutor,
     async_io::block_on,
-    async_net::unix::UnixDatagram,
-    backend::{Backend, Event, Proxy, Request, Stream, all, unix_socket},
-    cec_rs::{
-        CecConnection, CecConnectionCfgBuilder, CecConnectionResultError, CecDeviceType,
-        CecDeviceTypeVec, CecLogLevel, TryFromCecAudioStatusError,
+    cec_sync::{
+        CecError, MacroCommand, SceneCommand, Service,
+        config::Config,
+        keymap::Key,
+        rules::{Facts, Trigger},
+        state::{self, Store},
     },
-    clap::{Parser, Subcommand, command},
-    futures_util::{StreamExt, try_join},
-    macro_command::MacroCommand,
-    postcard::experimental::max_size::MaxSize,
+    clap::{Parser, Subcommand},
     std::{
-        fmt::Debug,
-        io::{self, ErrorKind},
+        path::{Path, PathBuf},
         process::ExitCode,
-        sync::Arc,
     },
 };
+#[cfg(feature = "unix-socket")]
+use {
+    cec_sync::{
+        backend::unix_socket::{self, Message},
+        keymap::SleepTimerAction,
+    },
+    std::io::{self, ErrorKind},
+};
 
 fn main() -> ExitCode {
-    match block_on(Args::parse().command.unwrap_or_default().run()) {
+    let args = Args::parse();
+    match block_on(args.command.unwrap_or_default().run(args.config.as_deref())) {
         Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
             log_error(err);
@@ -35,6 +35,14 @@ fn main() -> ExitCode {
 
 #[derive(Parser)]
 struct Args {
+    #[arg(
+        short,
+        long,
+        global = true,
+        help = "Path to the config file [default: $XDG_CONFIG_HOME/cec-sync/config.toml]"
+    )]
+    config: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Option<Command>,
 }
@@ -42,234 +50,252 @@ struct Args {
 #[derive(Subcommand)]
 enum Command {
     #[command(about = "Run the cec-sync service [default]")]
-    Serve,
+    Serve(Serve),
+
+    #[cfg(feature = "unix-socket")]
+    #[command(about = "Reload the config of the running cec-sync service")]
+    Reload,
+
+    #[cfg(feature = "unix-socket")]
+    #[command(about = "Run a sequence from the config in the running cec-sync service")]
+    Run {
+        #[arg(value_parser = parse_name)]
+        name: unix_socket::Name,
+    },
+
+    #[command(subcommand, about = "Save or restore the state of the devices")]
+    Scene(SceneCommand),
+
+    #[cfg(feature = "unix-socket")]
+    #[command(about = "Pause the players and put the devices in standby after some time")]
+    SleepTimer {
+        #[arg(help = "Time until the timer expires (eg. 45m, 1h30m), or `cancel`")]
+        action: SleepTimerAction,
+    },
+
+    #[command(about = "Show the state known by the running cec-sync service, or the saved state")]
+    Status,
+
+    #[command(subcommand, about = "Check the rules from the config")]
+    Rules(Rules),
 
     #[command(flatten)]
     Macro(MacroCommand),
 }
 
 impl Command {
-    pub async fn run(self) -> Result<(), Error> {
+    pub async fn run(self, config: Option<&Path>) -> Result<(), Error> {
         match self {
-            Command::Serve => serve().await,
-            Command::Macro(command) => send_or_run(command).await,
+            Command::Serve(args) => serve(config, args).await,
+            #[cfg(feature = "unix-socket")]
+            Command::Reload => unix_socket::Backend::send(Message::Reload)
+                .await
+                .map_err(Error::Send),
+            #[cfg(feature = "unix-socket")]
+            Command::Run { name } => unix_socket::Backend::send(Message::Sequence(name))
+                .await
+                .map_err(Error::Send),
+            #[cfg(feature = "unix-socket")]
+            Command::SleepTimer { action } => {
+                unix_socket::Backend::send(Message::SleepTimer(action))
+                    .await
+                    .map_err(Error::Send)
+            }
+            Command::Status => status().await,
+            Command::Scene(command) => scene(&Config::load(config)?, command).await,
+            Command::Rules(Rules::Test { trigger, key }) => {
+                test_rules(&Config::load(config)?, trigger, key)
+            }
+            Command::Macro(command) => send_or_run(&Config::load(config)?, command).await,
         }
     }
 }
 
 impl Default for Command {
     fn default() -> Self {
-        Command::Serve
+        Command::Serve(Serve::default())
     }
 }
 
-async fn serve() -> Result<(), Error> {
-    let (tx, rx) = async_channel::unbounded();
-
-    let backend = all::Backend::new(()).await?;
-    let (mut proxy, stream) = backend.split().await?;
-    let local_ex = LocalExecutor::new();
-
-    let input_task = local_ex.spawn(async move {
-        while let Ok(event) = rx.recv().await {
-            proxy.event(&event).await?;
-            if let Event::LogMessage(log_message) = event {
-                eprintln!(
-                    "{}: cec: {}",
-                    match log_message.level {
-                        CecLogLevel::Error =>
-                            return Err(Error::Cec(CecError::Log(log_message.message))),
-                        CecLogLevel::Warning => "warning",
-                        CecLogLevel::Notice => "notice",
-                        CecLogLevel::Traffic => "traffic",
-                        CecLogLevel::Debug => "debug",
-                        CecLogLevel::All => unreachable!(),
-                    },
-                    log_message.message
-                )
-            }
-        }
-
-        Ok(())
-    });
-
-    let output_task = local_ex.spawn(async move {
-        // NOTE: For now, this assumes that there's only ever one
-        // HDMI port that supports CEC.
-        //
-        // If we want to support multiple, we need to decide how
-        // to tell the backends what port an event came from.
-        // (would iHDMIPort from the CEC configuration work?)
-        //
-        // We also need to carefully consider what should be
-        // handled globally vs. per-display.
-        //
-        // eg. Two different connected TVs could have different
-        // volumes. It should be possible to adjust each
-        // individually.
-        let mut cec = cec_build(cec_config_evented(tx.clone()))?;
-        let mut stream = stream.into_stream();
-        while let Some(action) = stream.next().await {
-            match action? {
-                Request::ResetDevice(port) => {
-                    // Explicitly drop old cec connection to
-                    // make sure it doesn't keep a lock on the
-                    // device when we create a new connection
-                    cec = None;
-                    let _ = cec;
-
-                    let config = cec_config_evented(tx.clone());
-                    let config = match port {
-                        Some(port) => config.port(port),
-                        None => config,
-                    };
-
-                    cec = cec_build(config)?;
-                }
-                Request::RemoveDevice(_) => cec = None,
-                Request::Macro(command) => {
-                    if let Some(cec) = &cec {
-                        command.run(cec.clone()).await?;
-                    }
-                }
-            }
-        }
+#[derive(clap::Args, Default)]
+struct Serve {
+    #[arg(long, help = "Replace an already running cec-sync service")]
+    replace: bool,
 
-        Ok(())
-    });
+    #[arg(
+        long,
+        help = "Run as a system service, listening on /run/cec-sync/cec-sync"
+    )]
+    system: bool,
+}
 
-    local_ex
-        .run(async { try_join!(input_task, output_task) })
-        .await?;
+async fn serve(config_path: Option<&Path>, args: Serve) -> Result<(), Error> {
+    let mut builder = Service::builder().system(args.system).replace(args.replace);
+    if let Some(config_path) = config_path {
+        builder = builder.config(config_path);
+    }
 
+    builder.build()?.run().await?;
     Ok(())
 }
 
-async fn send_or_run(command: MacroCommand) -> Result<(), Error> {
-    match send(command).await {
-        Ok(()) => return Ok(()),
-        Err(err)
-            if match err.kind() {
-                ErrorKind::NotFound | ErrorKind::ConnectionRefused => true,
-                _ => false,
-            } =>
-        {
-            log_notice(
-                Error::Send(err),
-                "falling back to a direct CEC connection...",
-            );
-        }
-        Err(err) => log_error(Error::Send(err)),
+#[cfg(feature = "unix-socket")]
+fn parse_name(name: &str) -> Result<unix_socket::Name, String> {
+    name.parse().map_err(|()| {
+        let max = unix_socket::Name::new().capacity();
+        format!("names are at most {max} bytes long")
+    })
+}
+
+#[derive(Subcommand)]
+enum Rules {
+    #[command(
+        about = "Show which rules would run on a trigger with the current state, without running them"
+    )]
+    Test {
+        #[arg(help = "Only test the rules with this trigger, eg. tv-power-off or 23:00")]
+        trigger: Option<Trigger>,
+
+        #[arg(long, help = "Key that triggered the rules")]
+        key: Option<Key>,
+    },
+}
+
+fn test_rules(config: &Config, trigger: Option<Trigger>, key: Option<Key>) -> Result<(), Error> {
+    let store = Store::load()?;
+    let state = store.get();
+    let facts = Facts {
+        state: &state,
+        key,
+        // Key presses are only known by the running service
+        idle: None,
     };
 
-    let cec = Arc::new(cec_config().build().unwrap().open()?);
-    command.run(cec).await?;
+    let rules = config.rules.iter().enumerate();
+    for (index, rule) in rules.filter(|(_, rule)| trigger.is_none_or(|trigger| rule.on == trigger))
+    {
+        let unmet = rule.conditions.unmet(&facts);
+        match unmet.is_empty() {
+            true => println!("{}: would run {:?}", rule.label(index), rule.actions),
+            false => println!("{}: skipped, {}", rule.label(index), unmet.join(", ")),
+        }
+    }
     Ok(())
 }
 
-async fn send(command: MacroCommand) -> Result<(), io::Error> {
-    let socket = UnixDatagram::unbound()?;
-
-    // Serialization should never fail
-    let mut buf = [0u8; MacroCommand::POSTCARD_MAX_SIZE];
-    let command = postcard::to_slice(&command, &mut buf).unwrap();
+async fn send_or_run(config: &Config, command: MacroCommand) -> Result<(), Error> {
+    #[cfg(feature = "unix-socket")]
+    if send(Message::Macro(command)).await {
+        return Ok(());
+    }
 
-    let path = unix_socket::Backend::path();
-    socket.send_to(&command, &path).await?;
+    let max_volume = config.volume.max();
+    command
+        .run(cec_sync::connect(&config.cec)?, max_volume)
+        .await?;
     Ok(())
 }
 
-fn cec_config_evented(tx: Sender<Event>) -> CecConnectionCfgBuilder {
-    let key_press_tx = tx.clone();
-    let command_tx = tx.clone();
-    let log_message_tx = tx;
-    cec_config()
-        .key_press_callback(Box::new(move |key_press| {
-            let _ = key_press_tx.try_send(Event::KeyPress(key_press));
-        }))
-        .command_received_callback(Box::new(move |command| {
-            let _ = command_tx.try_send(Event::Command(command));
-        }))
-        .log_message_callback(Box::new(move |log_message| {
-            let _ = log_message_tx.try_send(Event::LogMessage(log_message));
-        }))
-}
+/// The state is queried from the service if it's running, since the
+/// state file is only saved a moment after it changes
+async fn status() -> Result<(), Error> {
+    #[cfg(feature = "unix-socket")]
+    match unix_socket::Backend::status().await {
+        Ok(state) => {
+            print!("{state}");
+            return Ok(());
+        }
+        Err(err)
+            if matches!(
+                err.kind(),
+                ErrorKind::NotFound | ErrorKind::ConnectionRefused
+            ) => {}
+        Err(err) => return Err(Error::Query(err)),
+    }
 
-fn cec_config() -> CecConnectionCfgBuilder {
-    CecConnectionCfgBuilder::default()
-        .device_name("cec-sync".to_owned())
-        .device_types(CecDeviceTypeVec::new(CecDeviceType::PlaybackDevice))
+    let store = Store::load()?;
+    // Serializing plain data should never fail
+    print!("{}", toml::to_string(&*store.get()).unwrap());
+    Ok(())
 }
 
-fn cec_build(
-    config: CecConnectionCfgBuilder,
-) -> Result<Option<Arc<CecConnection>>, CecConnectionResultError> {
-    Ok(match config.build().unwrap().open() {
-        Ok(cec) => Some(Arc::new(cec)),
-        Err(
-            err @ CecConnectionResultError::LibInitFailed
-            | err @ CecConnectionResultError::CallbackRegistrationFailed,
-        ) => {
-            return Err(err);
+/// Scenes are saved in the state of the service if it's running, and
+/// directly in the state file otherwise
+async fn scene(config: &Config, command: SceneCommand) -> Result<(), Error> {
+    #[cfg(feature = "unix-socket")]
+    {
+        let message = match &command {
+            SceneCommand::Save { name } => {
+                Message::SaveScene(parse_name(name).map_err(Error::Name)?)
+            }
+            SceneCommand::Restore { name } => {
+                Message::RestoreScene(parse_name(name).map_err(Error::Name)?)
+            }
+        };
+        if send(message).await {
+            return Ok(());
         }
-        Err(err) => {
-            log_notice(err, "waiting for adapter...");
-            None
+    }
+
+    let store = Store::load()?;
+    let max_volume = config.volume.max();
+    command
+        .run(cec_sync::connect(&config.cec)?, &store, max_volume)
+        .await?;
+    store.flush().await;
+    Ok(())
+}
+
+/// Send a message to the running service, returning whether it was sent
+#[cfg(feature = "unix-socket")]
+async fn send(message: Message) -> bool {
+    match unix_socket::Backend::send(message).await {
+        Ok(()) => return true,
+        Err(err)
+            if matches!(
+                err.kind(),
+                ErrorKind::NotFound | ErrorKind::ConnectionRefused
+            ) =>
+        {
+            log_notice(
+                Error::Send(err),
+                "falling back to a direct CEC connection...",
+            );
         }
-    })
+        Err(err) => log_error(Error::Send(err)),
+    };
+
+    false
 }
 
 fn log_error<E: Into<Error>>(err: E) {
     eprintln!("error: {}", err.into());
 }
 
+#[cfg(feature = "unix-socket")]
 fn log_notice<E: Into<Error>>(err: E, recovery_message: &str) {
     eprintln!("notice: {}, {}", err.into(), recovery_message);
 }
 
 #[derive(thiserror::Error, Debug)]
 enum Error {
+    #[error(transparent)]
+    Service(#[from] cec_sync::Error),
     #[error("cec: {0}")]
     Cec(#[from] CecError),
-    #[error(transparent)]
-    Backend(#[from] all::Error),
+    #[error("config: {0}")]
+    Config(#[from] cec_sync::config::Error),
+    #[error("state: {0}")]
+    State(#[from] state::Error),
+    #[error("scene: {0}")]
+    Scene(#[from] cec_sync::scene::Error),
+    #[cfg(feature = "unix-socket")]
+    #[error("{0}")]
+    Name(String),
+    #[cfg(feature = "unix-socket")]
     #[error("failed to send to cec-sync service: {0}")]
     Send(io::Error),
-}
-
-impl From<CecConnectionResultError> for Error {
-    fn from(value: CecConnectionResultError) -> Self {
-        Self::Cec(CecError::Connection(value))
-    }
-}
-
-#[derive(thiserror::Error, Debug)]
-pub enum CecError {
-    #[error("{}", match .0 {
-        CecConnectionResultError::LibInitFailed => "init failed",
-        CecConnectionResultError::CallbackRegistrationFailed => "callback registration failed",
-        CecConnectionResultError::NoAdapterFound => "no adapter found",
-        CecConnectionResultError::AdapterOpenFailed => "failed to open adapter",
-        CecConnectionResultError::TransmitFailed => "transmit failed",
-    })]
-    Connection(CecConnectionResultError),
-    #[error("{}", match .0 {
-        TryFromCecAudioStatusError::Unknown => "unknown audio status",
-        TryFromCecAudioStatusError::Reserved(_) => "reserved audio status",
-    })]
-    AudioStatus(TryFromCecAudioStatusError),
-    #[error("{0}")]
-    Log(String),
-}
-
-impl From<CecConnectionResultError> for CecError {
-    fn from(value: CecConnectionResultError) -> Self {
-        Self::Connection(value)
-    }
-}
-
-impl From<TryFromCecAudioStatusError> for CecError {
-    fn from(value: TryFromCecAudioStatusError) -> Self {
-        Self::AudioStatus(value)
-    }
+    #[cfg(feature = "unix-socket")]
+    #[error("failed to query cec-sync service: {0}")]
+    Query(io::Error),
 }
@@ -0,0 +1,244 @@
+//! Automation rules, running actions when something happens and some
+//! conditions are met
+
+use {
+    crate::{
+        config::HookEvent,
+        keymap::{self, Action, Key},
+        state::State,
+    },
+    jiff::{ToSpan, Zoned},
+    serde::{
+        Deserialize, Deserializer,
+        de::{IntoDeserializer, value},
+    },
+    std::{str::FromStr, time::Duration},
+};
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Rule {
+    /// Name used in logs
+    #[serde(default)]
+    pub name: Option<String>,
+
+    pub on: Trigger,
+
+    #[serde(default, rename = "if")]
+    pub conditions: Conditions,
+
+    /// Actions run in order, each one offered to the backends like a key
+    /// binding
+    #[serde(rename = "do")]
+    pub actions: Vec<Action>,
+
+    /// Only run once the trigger stopped firing for this long, checking
+    /// the conditions then
+    #[serde(default)]
+    pub debounce_ms: u64,
+}
+
+impl Rule {
+    /// Name of the rule, or its position in the config if it has none
+    pub fn label(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => format!("{name:?}"),
+            None => format!("#{}", index + 1),
+        }
+    }
+}
+
+/// What starts a rule: one of the events hooks run on (eg.
+/// `tv-power-off`), `player-status`, `sleep`, `resume`, or a time of day
+/// (`HH:MM`, local time)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    Event(HookEvent),
+
+    /// The playback status of an MPRIS player changed
+    PlayerStatus,
+
+    /// The system is about to sleep
+    Sleep,
+
+    /// The system resumed from sleep
+    Resume,
+
+    Time(TimeOfDay),
+}
+
+impl FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Ok(match name {
+            "player-status" => Trigger::PlayerStatus,
+            "sleep" => Trigger::Sleep,
+            "resume" => Trigger::Resume,
+            name if name.contains(':') => Trigger::Time(name.parse()?),
+            name => Trigger::Event(
+                HookEvent::deserialize(IntoDeserializer::<value::Error>::into_deserializer(name))
+                    .map_err(|_| format!("unknown trigger {name:?}"))?,
+            ),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Trigger {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        keymap::from_str(deserializer)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay {
+    pub hour: i8,
+    pub minute: i8,
+}
+
+impl TimeOfDay {
+    pub fn now() -> Self {
+        let now = Zoned::now();
+        Self {
+            hour: now.hour(),
+            minute: now.minute(),
+        }
+    }
+
+    /// Next time it's this time of day after `now`, on the following
+    /// day if it can't be on the first one (eg. because of DST)
+    pub fn next_after(&self, now: &Zoned) -> Result<Zoned, jiff::Error> {
+        let mut error = None;
+        for days in 0..3 {
+            let next = now.date().checked_add(days.days()).and_then(|date| {
+                let time = date.at(self.hour, self.minute, 0, 0);
+                time.to_zoned(now.time_zone().clone())
+            });
+            match next {
+                Ok(next) if next > *now => return Ok(next),
+                Ok(_) => (),
+                Err(err) => error = Some(err),
+            }
+        }
+
+        // The third day is always after now, so one of them failed
+        Err(error.unwrap())
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(time: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid time of day {time:?}, expected HH:MM");
+        let (hour, minute) = time.split_once(':').ok_or_else(invalid)?;
+        let hour = hour.parse().map_err(|_| invalid())?;
+        let minute = minute.parse().map_err(|_| invalid())?;
+        if !(0..24).contains(&hour) || !(0..60).contains(&minute) {
+            return Err(invalid());
+        }
+
+        Ok(Self { hour, minute })
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        keymap::from_str(deserializer)
+    }
+}
+
+/// Conditions that all have to be met for a rule to run
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Conditions {
+    /// Logical address of the active source
+    pub active_source: Option<u8>,
+
+    pub tv_power: Option<PowerStatus>,
+
+    /// Whether any MPRIS player is playing
+    pub playing: Option<bool>,
+
+    /// Key that triggered a `key` rule
+    pub key: Option<Key>,
+
+    /// Minimum time since the last key press
+    pub idle_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PowerStatus {
+    On,
+    Off,
+}
+
+/// What conditions are checked against
+pub struct Facts<'a> {
+    pub state: &'a State,
+
+    /// Key that triggered the rule
+    pub key: Option<Key>,
+
+    /// Time since the last key press, `None` if it isn't known
+    pub idle: Option<Duration>,
+}
+
+impl Conditions {
+    /// Descriptions of the conditions that aren't met
+    pub fn unmet(&self, facts: &Facts) -> Vec<String> {
+        let mut unmet = Vec::new();
+
+        if let Some(address) = self.active_source
+            && facts.state.active_source != Some(address)
+        {
+            unmet.push(match facts.state.active_source {
+                Some(active_source) => format!("active source is {active_source}, not {address}"),
+                None => format!("active source is unknown, not {address}"),
+            });
+        }
+
+        if let Some(power) = self.tv_power {
+            let tv_power = facts.state.tv_power.map(|on| match on {
+                true => PowerStatus::On,
+                false => PowerStatus::Off,
+            });
+            match tv_power {
+                Some(tv_power) if tv_power == power => (),
+                Some(tv_power) => unmet.push(format!("TV power is {tv_power:?}, not {power:?}")),
+                None => unmet.push(format!("TV power is unknown, not {power:?}")),
+            }
+        }
+
+        if let Some(playing) = self.playing {
+            let any_playing = facts
+                .state
+                .mpris
+                .players
+                .values()
+                .any(|player| player.playback_status == "Playing");
+            if any_playing != playing {
+                unmet.push(match any_playing {
+                    true => String::from("a player is playing"),
+                    false => String::from("no player is playing"),
+                });
+            }
+        }
+
+        if let Some(key) = self.key
+            && facts.key != Some(key)
+        {
+            unmet.push(format!("key isn't {key}"));
+        }
+
+        if let Some(idle_ms) = self.idle_ms
+            && let Some(idle) = facts.idle
+            && idle < Duration::from_millis(idle_ms)
+        {
+            unmet.push(format!("only idle for {}ms", idle.as_millis()));
+        }
+
+        unmet
+    }
+}
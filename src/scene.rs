@@ -0,0 +1,246 @@
+//! Snapshots of the bus state that can be restored later, eg. to reset
+//! the living room after guests
+
+use {
+    crate::{
+        macro_command::{MacroCommand, Mute, Volume},
+        service::CecError,
+        state::{Audio, Store},
+    },
+    arrayvec::ArrayVec,
+    blocking::unblock,
+    cec_rs::{
+        CecCommand, CecConnection, CecDatapacket, CecDeviceType, CecLogicalAddress,
+        CecLogicalAddresses, CecOpcode, CecPowerStatus, KnownAndRegisteredCecLogicalAddress,
+    },
+    clap::Subcommand,
+    serde::{Deserialize, Serialize},
+    std::{sync::Arc, time::Duration},
+};
+
+/// Addresses of the devices that can be on the bus
+const DEVICES: [CecLogicalAddress; 13] = [
+    CecLogicalAddress::Tv,
+    CecLogicalAddress::Recordingdevice1,
+    CecLogicalAddress::Recordingdevice2,
+    CecLogicalAddress::Tuner1,
+    CecLogicalAddress::Playbackdevice1,
+    CecLogicalAddress::Audiosystem,
+    CecLogicalAddress::Tuner2,
+    CecLogicalAddress::Tuner3,
+    CecLogicalAddress::Playbackdevice2,
+    CecLogicalAddress::Recordingdevice3,
+    CecLogicalAddress::Tuner4,
+    CecLogicalAddress::Playbackdevice3,
+    CecLogicalAddress::Freeuse,
+];
+
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum SceneCommand {
+    #[command(about = "Save the active source, power and audio status of the devices")]
+    Save { name: String },
+
+    #[command(about = "Restore a saved scene")]
+    Restore { name: String },
+}
+
+impl SceneCommand {
+    /// Run the command, restoring the volume up to `max_volume` at most
+    pub async fn run(
+        self,
+        cec: Arc<CecConnection>,
+        store: &Store,
+        max_volume: Option<u8>,
+    ) -> Result<(), Error> {
+        match self {
+            SceneCommand::Save { name } => {
+                let active_source = {
+                    let state = store.get();
+                    state
+                        .active_source
+                        .zip(state.active_source_physical_address)
+                };
+                let scene = unblock(move || Scene::capture(&cec, active_source)).await?;
+                store.update(|state| {
+                    state.scenes.insert(name, scene);
+                });
+            }
+            SceneCommand::Restore { name } => {
+                let scene = store.get().scenes.get(&name).cloned();
+                let scene = scene.ok_or(Error::UnknownScene(name))?;
+                scene.restore(cec, max_volume).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Scene {
+    /// Logical addresses of the devices that were on
+    pub on: Vec<u8>,
+
+    /// Logical addresses of the devices that were in standby
+    pub standby: Vec<u8>,
+
+    pub active_source: Option<Source>,
+
+    pub audio: Option<Audio>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    ThisDevice,
+
+    /// Another device, which is switched to by its physical address
+    PhysicalAddress(u16),
+}
+
+impl Scene {
+    /// Query the devices on the bus
+    ///
+    /// libcec only knows the logical address of the active source, so
+    /// its physical address has to come from its last `Active Source`
+    /// broadcast (`active_source`, as logical and physical addresses).
+    fn capture(cec: &CecConnection, active_source: Option<(u8, u16)>) -> Result<Self, CecError> {
+        let own_addresses = logical_addresses(cec).addresses;
+        let is_own = |address| {
+            KnownAndRegisteredCecLogicalAddress::new(address)
+                .is_some_and(|address| own_addresses.contains(&address))
+        };
+
+        let mut scene = Self::default();
+        for device in DEVICES.into_iter().filter(|&device| !is_own(device)) {
+            match cec.get_device_power_status(device) {
+                CecPowerStatus::On | CecPowerStatus::InTransitionStandbyToOn => {
+                    scene.on.push(device.repr() as u8);
+                }
+                CecPowerStatus::Standby | CecPowerStatus::InTransitionOnToStandby => {
+                    scene.standby.push(device.repr() as u8);
+                }
+                CecPowerStatus::Unknown => (),
+            }
+        }
+
+        scene.active_source = match cec.get_active_source() {
+            CecLogicalAddress::Unknown => None,
+            address if is_own(address) => Some(Source::ThisDevice),
+            address => match active_source {
+                Some((logical, physical)) if logical == address.repr() as u8 => {
+                    Some(Source::PhysicalAddress(physical))
+                }
+                _ => {
+                    eprintln!(
+                        "notice: scene: physical address of the active source ({address:?}) is unknown, not saving it..."
+                    );
+                    None
+                }
+            },
+        };
+
+        scene.audio = cec.audio_get_status().ok().map(|status| Audio {
+            volume: status.volume(),
+            muted: status.is_muted(),
+        });
+
+        Ok(scene)
+    }
+
+    /// Power the devices on or off first, since devices in standby can't
+    /// be switched to, then switch to the active source and set the volume
+    async fn restore(
+        self,
+        cec: Arc<CecConnection>,
+        max_volume: Option<u8>,
+    ) -> Result<(), CecError> {
+        let audio = self.audio;
+        let bus = cec.clone();
+        unblock(move || {
+            self.restore_power(&bus)?;
+            self.restore_routing(&bus)
+        })
+        .await?;
+
+        if let Some(audio) = audio {
+            MacroCommand::Volume(Volume::Set {
+                volume: audio.volume,
+            })
+            .run(cec.clone(), max_volume)
+            .await?;
+            MacroCommand::Mute {
+                command: Some(match audio.muted {
+                    true => Mute::On,
+                    false => Mute::Off,
+                }),
+            }
+            .run(cec, max_volume)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    fn restore_power(&self, cec: &CecConnection) -> Result<(), CecError> {
+        // Only send what's needed, powering on some devices toggles them
+        for device in devices(&self.on) {
+            if !is_on(cec.get_device_power_status(device)) {
+                cec.send_power_on_devices(device)?;
+            }
+        }
+        for device in devices(&self.standby) {
+            if is_on(cec.get_device_power_status(device)) {
+                cec.send_standby_devices(device)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn restore_routing(&self, cec: &CecConnection) -> Result<(), CecError> {
+        match self.active_source {
+            Some(Source::ThisDevice) => cec.set_active_source(CecDeviceType::Reserved)?,
+            Some(Source::PhysicalAddress(address)) => cec.transmit(CecCommand {
+                initiator: logical_addresses(cec).primary.into(),
+                destination: CecLogicalAddress::Unregistered,
+                ack: false,
+                eom: true,
+                opcode: CecOpcode::SetStreamPath,
+                parameters: CecDatapacket(ArrayVec::from_iter(address.to_be_bytes())),
+                opcode_set: true,
+                transmit_timeout: Duration::from_secs(1),
+            })?,
+            None => (),
+        }
+
+        Ok(())
+    }
+}
+
+fn logical_addresses(cec: &CecConnection) -> CecLogicalAddresses {
+    // This would only fail if there was a bug in cec-rs or libcec
+    cec.get_logical_addresses().unwrap()
+}
+
+fn devices(addresses: &[u8]) -> impl Iterator<Item = CecLogicalAddress> {
+    DEVICES
+        .into_iter()
+        .filter(|device| addresses.contains(&(device.repr() as u8)))
+}
+
+fn is_on(status: CecPowerStatus) -> bool {
+    matches!(
+        status,
+        CecPowerStatus::On | CecPowerStatus::InTransitionStandbyToOn
+    )
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("cec: {0}")]
+    Cec(#[from] CecError),
+    #[error("unknown scene {0:?}")]
+    UnknownScene(String),
+}
@@ -0,0 +1,97 @@
+//! Minimal implementation of the systemd service notification protocol
+//!
+//! See sd_notify(3)
+
+use std::{
+    env,
+    ffi::OsString,
+    io,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    time::Duration,
+};
+
+/// Variables of socket activation, which are meant for the service and
+/// not for its child processes, like with
+/// sd_listen_fds(unset_environment=1)
+///
+/// See sd_listen_fds(3)
+pub const LISTEN_ENV: [&str; 3] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"];
+
+pub struct Notifier {
+    inner: Option<(UnixDatagram, SocketAddr)>,
+}
+
+impl Notifier {
+    /// Connect to the socket in `NOTIFY_SOCKET`, or do nothing if
+    /// the service wasn't started by a service manager
+    pub fn from_env() -> io::Result<Self> {
+        let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+            return Ok(Self { inner: None });
+        };
+
+        let addr = Self::parse_addr(path)?;
+        Ok(Self {
+            inner: Some((UnixDatagram::unbound()?, addr)),
+        })
+    }
+
+    fn parse_addr(path: OsString) -> io::Result<SocketAddr> {
+        match path.as_bytes() {
+            [b'@', name @ ..] => SocketAddr::from_abstract_name(name),
+            [b'/', ..] => SocketAddr::from_pathname(path),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "NOTIFY_SOCKET must be an absolute path or an abstract socket name",
+            )),
+        }
+    }
+
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    pub fn reloading(&self) {
+        self.notify("RELOADING=1");
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={status}"));
+    }
+
+    pub fn watchdog(&self) {
+        self.notify("WATCHDOG=1");
+    }
+
+    fn notify(&self, state: &str) {
+        if let Some((socket, addr)) = &self.inner {
+            // The service manager doesn't reply, and failing to notify
+            // it shouldn't take the service down
+            let _ = socket.send_to_addr(state.as_bytes(), addr);
+        }
+    }
+}
+
+/// How often the service manager expects a watchdog ping, if enabled
+pub fn watchdog_timeout() -> Option<Duration> {
+    if let Some(pid) = env::var_os("WATCHDOG_PID")
+        && pid.to_str().and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id())
+    {
+        return None;
+    }
+
+    env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .filter(|&usec| usec > 0)
+        .map(Duration::from_micros)
+}
@@ -0,0 +1,229 @@
+//! Named sequences of commands, run by the service one step after the
+//! other
+
+use {
+    crate::{
+        keymap,
+        macro_command::MacroCommand,
+        rules::{Conditions, Facts},
+        sd_notify::{self, Notifier},
+        service::CecError,
+        state::Store,
+    },
+    async_io::Timer,
+    async_process::Command,
+    cec_rs::CecConnection,
+    futures_util::{
+        StreamExt,
+        future::{self, Either, select},
+    },
+    serde::Deserialize,
+    std::{
+        future::Future,
+        io,
+        pin::pin,
+        process::ExitStatus,
+        sync::Arc,
+        time::{Duration, Instant},
+    },
+};
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Sequence {
+    pub steps: Vec<Step>,
+
+    /// What to do when a step fails
+    pub on_error: OnError,
+
+    /// Time after which a `wait-for` step fails
+    pub wait_timeout_ms: u64,
+
+    /// Time after which a `shell` step is killed and fails
+    pub shell_timeout_ms: u64,
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Self {
+            steps: Vec::new(),
+            on_error: OnError::default(),
+            wait_timeout_ms: 10000,
+            shell_timeout_ms: 10000,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Step {
+    /// Run a CEC command, written like on the command line
+    /// (eg. `"volume set 30"`)
+    Macro(#[serde(deserialize_with = "keymap::from_str")] MacroCommand),
+
+    /// Run a command with `sh -c`, waiting for it to exit
+    Shell(String),
+
+    /// Wait before the next step
+    DelayMs(u64),
+
+    /// Wait until the conditions are met, like the ones of rules
+    WaitFor(Conditions),
+}
+
+#[derive(Deserialize, Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnError {
+    /// Skip the remaining steps
+    #[default]
+    Abort,
+
+    /// Log the error and run the next step
+    Continue,
+}
+
+impl Sequence {
+    /// Run every step, only logging errors
+    ///
+    /// Other requests wait until the sequence is done, so nothing else
+    /// is sent to the bus in the middle of it.
+    pub(crate) async fn run(
+        &self,
+        name: &str,
+        cec: Option<&Arc<CecConnection>>,
+        state: &Store,
+        notifier: &Notifier,
+        max_volume: Option<u8>,
+    ) {
+        eprintln!("traffic: sequence {name:?}: running");
+
+        for (index, step) in self.steps.iter().enumerate() {
+            let step = step.run(cec, state, notifier, self.timeouts(), max_volume);
+            let Err(err) = step.await else {
+                continue;
+            };
+
+            eprintln!("error: sequence {name:?}: step {}: {err}", index + 1);
+            if self.on_error == OnError::Abort {
+                eprintln!("notice: sequence {name:?}: skipping the remaining steps");
+                return;
+            }
+        }
+    }
+
+    fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            wait: Duration::from_millis(self.wait_timeout_ms),
+            shell: Duration::from_millis(self.shell_timeout_ms),
+        }
+    }
+}
+
+struct Timeouts {
+    wait: Duration,
+    shell: Duration,
+}
+
+impl Step {
+    async fn run(
+        &self,
+        cec: Option<&Arc<CecConnection>>,
+        state: &Store,
+        notifier: &Notifier,
+        timeouts: Timeouts,
+        max_volume: Option<u8>,
+    ) -> Result<(), Error> {
+        match self {
+            Step::Macro(command) => {
+                let cec = cec.ok_or(Error::NoAdapter)?;
+                command.run(cec.clone(), max_volume).await?;
+            }
+            Step::Shell(command) => {
+                keep_alive(notifier, shell(command, timeouts.shell)).await?;
+            }
+            Step::DelayMs(delay_ms) => {
+                keep_alive(notifier, Timer::after(Duration::from_millis(*delay_ms))).await;
+            }
+            Step::WaitFor(conditions) => {
+                keep_alive(notifier, wait_for(conditions, state, timeouts.wait)).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Run a command with `sh -c`, killing it if it's still running after
+/// the timeout
+async fn shell(command: &str, timeout: Duration) -> Result<(), Error> {
+    let mut sh = Command::new("sh");
+    sh.arg("-c").arg(command).kill_on_drop(true);
+    for name in sd_notify::LISTEN_ENV {
+        sh.env_remove(name);
+    }
+    let mut child = sh
+        .spawn()
+        .map_err(|err| Error::Shell(command.to_owned(), err))?;
+
+    let status = match select(pin!(child.status()), Timer::after(timeout)).await {
+        Either::Left((status, _)) => status.map_err(|err| Error::Shell(command.to_owned(), err))?,
+        Either::Right(_) => return Err(Error::ShellTimeout(command.to_owned())),
+    };
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::Exit(command.to_owned(), status)),
+    }
+}
+
+async fn wait_for(conditions: &Conditions, state: &Store, timeout: Duration) -> Result<(), Error> {
+    let deadline = Instant::now() + timeout;
+    let changes = state.subscribe();
+
+    loop {
+        let unmet = conditions.unmet(&Facts {
+            state: &state.get(),
+            key: None,
+            idle: None,
+        });
+        if unmet.is_empty() {
+            return Ok(());
+        }
+
+        if let Either::Right(_) = select(pin!(changes.recv()), Timer::at(deadline)).await {
+            return Err(Error::Timeout(unmet.join(", ")));
+        }
+    }
+}
+
+/// Keep pinging the watchdog while waiting on purpose, unlike a request
+/// stuck in libcec
+async fn keep_alive<F: Future>(notifier: &Notifier, future: F) -> F::Output {
+    let Some(timeout) = sd_notify::watchdog_timeout() else {
+        return future.await;
+    };
+
+    let pings = Timer::interval(timeout / 2).for_each(|_| {
+        notifier.watchdog();
+        future::ready(())
+    });
+    match select(pin!(future), pings).await {
+        Either::Left((output, _)) => output,
+        Either::Right(_) => unreachable!(),
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("cec: {0}")]
+    Cec(#[from] CecError),
+    #[error("no CEC adapter")]
+    NoAdapter,
+    #[error("failed to run `{0}`: {1}")]
+    Shell(String, io::Error),
+    #[error("`{0}` exited with {1}")]
+    Exit(String, ExitStatus),
+    #[error("`{0}` timed out, killed it")]
+    ShellTimeout(String),
+    #[error("timed out waiting, {0}")]
+    Timeout(String),
+}
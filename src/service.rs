@@ -0,0 +1,795 @@
+use {
+    crate::{
+        backend::{
+            Backend, EitherError, Event, Proxy, Request, Stream,
+            registry::{self, Registry},
+        },
+        config::{self, Config},
+        instance::{self, Lock},
+        keymap::{Action, KeyEvent, Keymap},
+        macro_command::{self, MacroCommand},
+        scene,
+        sd_notify::{self, Notifier},
+        state::{self, State, Store},
+    },
+    async_channel::{Receiver, Sender},
+    async_io::Timer,
+    blocking::unblock,
+    cec_rs::{
+        CecConnection, CecConnectionCfgBuilder, CecConnectionResultError, CecDeviceType,
+        CecDeviceTypeVec, CecLogLevel, CecLogicalAddress, TryFromCecAudioStatusError,
+        TryFromCecLogicalAddressesError,
+    },
+    futures_util::{
+        FutureExt, StreamExt, TryStreamExt,
+        future::{Either, select},
+        stream,
+    },
+    std::{ffi::CString, io, path::PathBuf, pin::pin, process, rc::Rc, sync::Arc, time::Instant},
+};
+
+/// The cec-sync service, syncing the CEC bus with the built-in
+/// backends and an extra, embedder provided backend `B`
+///
+/// ```no_run
+/// # async fn run() -> Result<(), cec_sync::Error> {
+/// cec_sync::Service::builder().build()?.run().await
+/// # }
+/// ```
+pub struct Service<B: Backend = ()> {
+    builder: Builder<B>,
+    config: Config,
+    state: Rc<Store>,
+}
+
+impl Service {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+}
+
+impl<B> Service<B>
+where
+    B: Backend,
+    B::Error: std::error::Error + 'static,
+    for<'a> <B::Proxy<'a> as Proxy>::Error: std::error::Error + 'static,
+    for<'a> <B::Stream<'a> as Stream>::Error: std::error::Error + 'static,
+{
+    /// Run until the service receives a shutdown request
+    pub async fn run(self) -> Result<(), Error> {
+        let options = &self.builder.options;
+        let lock = Lock::acquire(options.system, options.replace).await?;
+
+        // Changes to the state are saved in the background, and once
+        // more on the way out, before another service can take over
+        let state = self.state.clone();
+        let result = match select(pin!(self.serve()), pin!(state.save_changes())).await {
+            Either::Left((result, _)) => result,
+            Either::Right((never, _)) => match never {},
+        };
+        state.flush().await;
+        drop(lock);
+        result
+    }
+
+    async fn serve(self) -> Result<(), Error> {
+        let Self {
+            builder: Builder { options, backend },
+            mut config,
+            state,
+        } = self;
+
+        let (tx, rx) = async_channel::unbounded();
+        let (responses_tx, responses_rx) = async_channel::unbounded();
+        let (actions_tx, actions_rx) = async_channel::unbounded();
+        let notifier = Notifier::from_env().map_err(Error::Notify)?;
+        notifier.status("Starting backends...");
+
+        let mut backend = <(Registry, B)>::new((options.context(&config, &state), backend)).await?;
+
+        // NOTE: For now, this assumes that there's only ever one
+        // HDMI port that supports CEC.
+        //
+        // If we want to support multiple, we need to decide how
+        // to tell the backends what port an event came from.
+        // (would iHDMIPort from the CEC configuration work?)
+        //
+        // We also need to carefully consider what should be
+        // handled globally vs. per-display.
+        //
+        // eg. Two different connected TVs could have different
+        // volumes. It should be possible to adjust each
+        // individually.
+        let mut adapter = Adapter::new(tx, config.cec.clone());
+        let mut opened = false;
+        let mut keymap = Keymap::new(&config.keymap);
+
+        loop {
+            let (mut proxy, stream) = backend.split().await?;
+            if !opened {
+                adapter.open(None)?;
+                opened = true;
+            }
+
+            notify_adapter_status(&notifier, &adapter);
+            notifier.ready();
+
+            let (stop, stopped) = async_channel::bounded::<()>(1);
+            let exit = match select(
+                pin!(dispatch_events(
+                    &mut proxy,
+                    &mut keymap,
+                    &rx,
+                    &actions_rx,
+                    &responses_tx,
+                    stopped.recv().map(drop),
+                )),
+                pin!(handle_requests(
+                    stream,
+                    &responses_rx,
+                    &actions_tx,
+                    &config,
+                    &state,
+                    &mut adapter,
+                    &notifier
+                )),
+            )
+            .await
+            {
+                Either::Left((result, _)) => result.map(|()| Exit::Shutdown)?,
+                Either::Right((Ok(Exit::Reload), events)) => {
+                    // Finish dispatching the current event, its responses
+                    // are handled after the reload
+                    stop.close();
+                    events.await?;
+                    Exit::Reload
+                }
+                Either::Right((result, _)) => result?,
+            };
+
+            // The backends are borrowed until they're split again
+            drop(proxy);
+
+            match exit {
+                Exit::Shutdown => break,
+                Exit::Reload => {
+                    notifier.reloading();
+                    notifier.status("Reloading config...");
+
+                    let new_config = match Config::load(options.config.as_deref()) {
+                        Ok(new_config) => new_config,
+                        Err(err) => {
+                            log_notice(err, "keeping the current config...");
+                            continue;
+                        }
+                    };
+
+                    // Only the built-in backends are configured by the
+                    // config file, so the extra backend is kept as is
+                    let ctx = options.context(&new_config, &state);
+                    if let Err(err) = apply(&mut backend.0, &mut adapter, ctx, &new_config).await {
+                        log_notice(err, "keeping the current config...");
+
+                        // Part of it may have been applied already
+                        let ctx = options.context(&config, &state);
+                        apply(&mut backend.0, &mut adapter, ctx, &config).await?;
+                        continue;
+                    }
+
+                    if new_config.keymap != config.keymap {
+                        keymap = Keymap::new(&new_config.keymap);
+                    }
+
+                    config = new_config;
+                }
+            }
+        }
+
+        if let Some(cec) = &adapter.cec {
+            for action in &config.shutdown.actions {
+                let command = MacroCommand::from(*action);
+                if let Err(err) = command.run(cec.clone(), config.volume.max()).await {
+                    log_error(err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Builder<B: Backend = ()> {
+    options: Options,
+    backend: B::Context,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            options: Options::default(),
+            backend: (),
+        }
+    }
+}
+
+impl<B: Backend> Builder<B> {
+    /// Path to the config file, instead of
+    /// `$XDG_CONFIG_HOME/cec-sync/config.toml`
+    pub fn config(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.config = Some(path.into());
+        self
+    }
+
+    /// Replace an already running cec-sync service
+    pub fn replace(mut self, replace: bool) -> Self {
+        self.options.replace = replace;
+        self
+    }
+
+    /// Run as a system service, without a user session
+    pub fn system(mut self, system: bool) -> Self {
+        self.options.system = system;
+        self
+    }
+
+    /// Add a backend, which is created from `ctx` when the service starts
+    ///
+    /// Backends can be added multiple times, they're combined in pairs.
+    pub fn backend<C: Backend>(self, ctx: C::Context) -> Builder<(B, C)> {
+        Builder {
+            options: self.options,
+            backend: (self.backend, ctx),
+        }
+    }
+
+    /// Load the config and state, ready to run the service
+    pub fn build(self) -> Result<Service<B>, Error> {
+        let config = Config::load(self.options.config.as_deref())?;
+        // The unreadable state file is replaced on the next change
+        let state = Rc::new(Store::load().unwrap_or_else(|err| {
+            log_notice(err, "starting with a fresh state...");
+            Store::new(Store::path(), State::default())
+        }));
+
+        Ok(Service {
+            builder: self,
+            config,
+            state,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    config: Option<PathBuf>,
+    replace: bool,
+    system: bool,
+}
+
+impl Options {
+    fn context(&self, config: &Config, state: &Rc<Store>) -> registry::Context {
+        registry::Context {
+            config: config.clone(),
+            #[cfg(feature = "scripting")]
+            config_path: self.config.clone().or_else(Config::path),
+            state: state.clone(),
+            #[cfg(any(feature = "unix-socket", feature = "mpris", feature = "wayland"))]
+            system: self.system,
+        }
+    }
+}
+
+enum Exit {
+    Shutdown,
+    Reload,
+}
+
+/// Apply a config to the built-in backends and the adapter
+async fn apply(
+    registry: &mut Registry,
+    adapter: &mut Adapter,
+    ctx: registry::Context,
+    config: &Config,
+) -> Result<(), Error> {
+    registry.reload(ctx).await?;
+
+    // libcec can't change its configuration on an open connection, so
+    // only reconnect if it changed
+    if adapter.config != config.cec {
+        adapter.config = config.cec.clone();
+        adapter.reopen()?;
+    }
+
+    Ok(())
+}
+
+/// Dispatch events and actions to the proxies until `stop` completes,
+/// which is only checked between events
+async fn dispatch_events<P>(
+    proxy: &mut P,
+    keymap: &mut Keymap,
+    rx: &Receiver<Event>,
+    actions: &Receiver<Action>,
+    responses: &Sender<Request>,
+    stop: impl Future<Output = ()>,
+) -> Result<(), Error>
+where
+    P: Proxy,
+    Error: From<P::Error>,
+{
+    let mut received = pin!(
+        stream::select(
+            rx.clone().map(Either::Left),
+            actions.clone().map(Either::Right)
+        )
+        .take_until(stop)
+    );
+    loop {
+        // Wake up for held keys and delayed presses too
+        let received = match keymap.deadline() {
+            Some(deadline) => match select(received.next(), Timer::at(deadline)).await {
+                Either::Left((received, _)) => received,
+                Either::Right(_) => {
+                    for key_event in keymap.timeout(Instant::now()) {
+                        dispatch_key(proxy, keymap, responses, key_event).await?;
+                    }
+                    continue;
+                }
+            },
+            None => received.next().await,
+        };
+        let event = match received {
+            Some(Either::Left(event)) => event,
+            Some(Either::Right(action)) => {
+                if !dispatch_actions(proxy, keymap, responses, vec![action.clone()]).await? {
+                    eprintln!("traffic: {action:?} not claimed");
+                }
+                continue;
+            }
+            None => break,
+        };
+
+        // Events are dispatched one at a time, so responses are queued
+        // in the same order as the events that triggered them
+        dispatch_event(proxy, responses, &event).await?;
+
+        if let Event::KeyPress(keypress) = event {
+            for key_event in keymap.key_press(keypress, Instant::now()) {
+                dispatch_key(proxy, keymap, responses, key_event).await?;
+            }
+        }
+
+        if let Event::LogMessage(log_message) = event {
+            eprintln!(
+                "{}: cec: {}",
+                match log_message.level {
+                    CecLogLevel::Error =>
+                        return Err(Error::Cec(CecError::Log(log_message.message))),
+                    CecLogLevel::Warning => "warning",
+                    CecLogLevel::Notice => "notice",
+                    CecLogLevel::Traffic => "traffic",
+                    CecLogLevel::Debug => "debug",
+                    CecLogLevel::All => unreachable!(),
+                },
+                log_message.message
+            )
+        }
+    }
+
+    Ok(())
+}
+
+/// Dispatch a key event, then offer the actions bound to it in order,
+/// until one is claimed
+async fn dispatch_key<P>(
+    proxy: &mut P,
+    keymap: &mut Keymap,
+    responses: &Sender<Request>,
+    key_event: KeyEvent,
+) -> Result<(), Error>
+where
+    P: Proxy,
+    Error: From<P::Error>,
+{
+    dispatch_event(proxy, responses, &Event::Key(key_event)).await?;
+
+    let bindings = keymap.bindings(key_event).to_vec();
+    if !bindings.is_empty() && !dispatch_actions(proxy, keymap, responses, bindings).await? {
+        eprintln!("traffic: keymap: {key_event:?} not claimed");
+    }
+    Ok(())
+}
+
+/// Offer a chain of actions in order until one is claimed, returning
+/// whether any was
+async fn dispatch_actions<P>(
+    proxy: &mut P,
+    keymap: &mut Keymap,
+    responses: &Sender<Request>,
+    chain: Vec<Action>,
+) -> Result<bool, Error>
+where
+    P: Proxy,
+    Error: From<P::Error>,
+{
+    for action in chain {
+        let requests = match action {
+            Action::Ignore => Vec::new(),
+            Action::Mode(mode) => {
+                keymap.switch_mode(&mode);
+                Vec::new()
+            }
+            Action::Macro(command) => vec![Request::Macro(command)],
+            Action::Shell(command) => vec![Request::Shell(command)],
+            Action::Sequence(name) => vec![Request::Sequence(name)],
+            action => match proxy.action(&action).await? {
+                Some(requests) => requests,
+                None => continue,
+            },
+        };
+
+        for request in requests {
+            let _ = responses.try_send(request);
+        }
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+async fn dispatch_event<P>(
+    proxy: &mut P,
+    responses: &Sender<Request>,
+    event: &Event,
+) -> Result<(), Error>
+where
+    P: Proxy,
+    Error: From<P::Error>,
+{
+    for request in proxy.event(event).await? {
+        // The receiver lives as long as the service
+        let _ = responses.try_send(request);
+    }
+
+    Ok(())
+}
+
+async fn handle_requests<S>(
+    stream: S,
+    responses: &Receiver<Request>,
+    actions: &Sender<Action>,
+    config: &Config,
+    state: &Store,
+    adapter: &mut Adapter,
+    notifier: &Notifier,
+) -> Result<Exit, Error>
+where
+    S: Stream,
+    Error: From<S::Error>,
+{
+    let mut stream = pin!(stream::select(
+        responses.clone().map(Ok),
+        stream.into_stream().map_err(Error::from),
+    ));
+    while let Some(request) = stream.next().await {
+        match request? {
+            Request::Shutdown => {
+                notifier.stopping();
+                notifier.status("Shutting down...");
+
+                // Flush any requests that were queued before the
+                // shutdown signal, without waiting for new ones
+                while let Some(Some(request)) = stream.next().now_or_never() {
+                    match request? {
+                        Request::Reload | Request::Shutdown => (),
+                        request => {
+                            handle_request(adapter, notifier, actions, config, state, request)
+                                .await?
+                        }
+                    }
+                }
+
+                return Ok(Exit::Shutdown);
+            }
+            Request::Reload => return Ok(Exit::Reload),
+            request => handle_request(adapter, notifier, actions, config, state, request).await?,
+        }
+    }
+
+    Ok(Exit::Shutdown)
+}
+
+async fn handle_request(
+    adapter: &mut Adapter,
+    notifier: &Notifier,
+    actions: &Sender<Action>,
+    config: &Config,
+    state: &Store,
+    request: Request,
+) -> Result<(), Error> {
+    match request {
+        Request::ResetDevice(port) => {
+            adapter.open(port)?;
+            notify_adapter_status(notifier, adapter);
+        }
+        Request::RemoveDevice(_) => {
+            adapter.close();
+            notify_adapter_status(notifier, adapter);
+        }
+        // A failed command doesn't stop the service, eg. logind waits
+        // for the ones sent before sleeping until its lock is released
+        Request::Macro(command) => {
+            if let Some(cec) = &adapter.cec
+                && let Err(err) = command.run(cec.clone(), config.volume.max()).await
+            {
+                log_error(err);
+            }
+        }
+        // Like macros, eg. the TV may reject an OSD string
+        Request::Transmit(mut command) => {
+            if let Some(cec) = adapter.cec.clone() {
+                let transmitted = unblock(move || {
+                    // Backends don't know the address of this device,
+                    // which is unknown while the adapter is being reset
+                    if command.initiator == CecLogicalAddress::Unknown {
+                        let addresses = cec.get_logical_addresses().map_err(CecError::from)?;
+                        command.initiator = addresses.primary.into();
+                    }
+                    cec.transmit(command).map_err(CecError::from)
+                })
+                .await;
+                if let Err(err) = transmitted {
+                    log_error(err);
+                }
+            }
+        }
+        Request::Shell(command) => spawn_shell(command),
+        Request::Sequence(name) => match config.sequences.get(&name) {
+            Some(sequence) => {
+                let cec = adapter.cec.as_ref();
+                let max_volume = config.volume.max();
+                sequence.run(&name, cec, state, notifier, max_volume).await
+            }
+            None => eprintln!("notice: unknown sequence {name:?}, ignoring..."),
+        },
+        Request::Scene(command) => match &adapter.cec {
+            Some(cec) => {
+                if let Err(err) = command.run(cec.clone(), state, config.volume.max()).await {
+                    log_error(err);
+                }
+            }
+            None => eprintln!("notice: no CEC adapter, ignoring {command:?}..."),
+        },
+        // Actions are offered to the proxies with the events, the
+        // receiver lives as long as the service
+        Request::Action(action) => {
+            let _ = actions.try_send(action);
+        }
+        Request::IfActiveSource(chain) => {
+            let active = match adapter.cec.clone() {
+                Some(cec) => unblock(move || macro_command::is_active_source(&cec)).await,
+                None => false,
+            };
+            match active {
+                true => {
+                    for action in chain {
+                        let _ = actions.try_send(action);
+                    }
+                }
+                false => eprintln!("traffic: not the active source, ignoring {chain:?}"),
+            }
+        }
+        Request::Watchdog => notifier.watchdog(),
+        Request::Reload | Request::Shutdown => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// Run a shell command in the background, only logging failures
+fn spawn_shell(command: String) {
+    let mut sh = process::Command::new("sh");
+    sh.arg("-c").arg(&command);
+    for name in sd_notify::LISTEN_ENV {
+        sh.env_remove(name);
+    }
+    let mut child = match sh.spawn() {
+        Ok(child) => child,
+        Err(err) => return log_error(Error::Shell(command, err)),
+    };
+
+    unblock(move || match child.wait() {
+        Ok(status) if status.success() => (),
+        Ok(status) => eprintln!("notice: shell: `{command}` exited with {status}"),
+        Err(err) => log_error(Error::Shell(command, err)),
+    })
+    .detach();
+}
+
+fn notify_adapter_status(notifier: &Notifier, adapter: &Adapter) {
+    notifier.status(match adapter.cec {
+        Some(_) => "Connected to CEC adapter",
+        None => "Waiting for CEC adapter...",
+    });
+}
+
+/// Connection to the CEC adapter, which is kept open across reloads
+struct Adapter {
+    tx: Sender<Event>,
+    config: config::Cec,
+    port: Option<CString>,
+    cec: Option<Arc<CecConnection>>,
+}
+
+impl Adapter {
+    fn new(tx: Sender<Event>, config: config::Cec) -> Self {
+        Self {
+            tx,
+            config,
+            port: None,
+            cec: None,
+        }
+    }
+
+    fn open(&mut self, port: Option<CString>) -> Result<(), Error> {
+        self.port = port;
+        self.reopen()
+    }
+
+    fn reopen(&mut self) -> Result<(), Error> {
+        // Explicitly drop old cec connection to
+        // make sure it doesn't keep a lock on the
+        // device when we create a new connection
+        self.close();
+
+        let config = cec_config_evented(&self.config, self.tx.clone());
+        let config = match self.port.clone() {
+            Some(port) => config.port(port),
+            None => config,
+        };
+
+        self.cec = cec_build(config)?;
+        if self.cec.is_some() {
+            let _ = self.tx.try_send(Event::AdapterConnected);
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        if self.cec.take().is_some() {
+            let _ = self.tx.try_send(Event::AdapterRemoved);
+        }
+    }
+}
+
+/// Open a connection to the CEC adapter directly, without the service
+pub fn connect(config: &config::Cec) -> Result<Arc<CecConnection>, CecError> {
+    Ok(Arc::new(cec_config(config).build().unwrap().open()?))
+}
+
+fn cec_config_evented(config: &config::Cec, tx: Sender<Event>) -> CecConnectionCfgBuilder {
+    let key_press_tx = tx.clone();
+    let command_tx = tx.clone();
+    let log_message_tx = tx;
+    cec_config(config)
+        .key_press_callback(Box::new(move |key_press| {
+            let _ = key_press_tx.try_send(Event::KeyPress(key_press));
+        }))
+        .command_received_callback(Box::new(move |command| {
+            let _ = command_tx.try_send(Event::Command(command));
+        }))
+        .log_message_callback(Box::new(move |log_message| {
+            let _ = log_message_tx.try_send(Event::LogMessage(log_message));
+        }))
+}
+
+fn cec_config(config: &config::Cec) -> CecConnectionCfgBuilder {
+    CecConnectionCfgBuilder::default()
+        .device_name(config.device_name.clone())
+        .device_types(CecDeviceTypeVec::new(CecDeviceType::PlaybackDevice))
+}
+
+fn cec_build(
+    config: CecConnectionCfgBuilder,
+) -> Result<Option<Arc<CecConnection>>, CecConnectionResultError> {
+    Ok(match config.build().unwrap().open() {
+        Ok(cec) => Some(Arc::new(cec)),
+        Err(
+            err @ CecConnectionResultError::LibInitFailed
+            | err @ CecConnectionResultError::CallbackRegistrationFailed,
+        ) => {
+            return Err(err);
+        }
+        Err(err) => {
+            log_notice(err, "waiting for adapter...");
+            None
+        }
+    })
+}
+
+fn log_error<E: Into<Error>>(err: E) {
+    eprintln!("error: {}", err.into());
+}
+
+fn log_notice<E: Into<Error>>(err: E, recovery_message: &str) {
+    eprintln!("notice: {}, {}", err.into(), recovery_message);
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("cec: {0}")]
+    Cec(#[from] CecError),
+    #[error(transparent)]
+    Backend(#[from] registry::Error),
+    #[error(transparent)]
+    Extra(Box<dyn std::error::Error>),
+    #[error("config: {0}")]
+    Config(#[from] config::Error),
+    #[error(transparent)]
+    Instance(#[from] instance::Error),
+    #[error("state: {0}")]
+    State(#[from] state::Error),
+    #[error("failed to notify service manager: {0}")]
+    Notify(io::Error),
+    #[error("failed to run `{0}`: {1}")]
+    Shell(String, io::Error),
+    #[error("scene: {0}")]
+    Scene(#[from] scene::Error),
+}
+
+impl<E: std::error::Error + 'static> From<EitherError<registry::Error, E>> for Error {
+    fn from(value: EitherError<registry::Error, E>) -> Self {
+        match value {
+            EitherError::Left(err) => Self::Backend(err),
+            EitherError::Right(err) => Self::Extra(Box::new(err)),
+        }
+    }
+}
+
+impl From<CecConnectionResultError> for Error {
+    fn from(value: CecConnectionResultError) -> Self {
+        Self::Cec(CecError::Connection(value))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CecError {
+    #[error("{}", match .0 {
+        CecConnectionResultError::LibInitFailed => "init failed",
+        CecConnectionResultError::CallbackRegistrationFailed => "callback registration failed",
+        CecConnectionResultError::NoAdapterFound => "no adapter found",
+        CecConnectionResultError::AdapterOpenFailed => "failed to open adapter",
+        CecConnectionResultError::TransmitFailed => "transmit failed",
+    })]
+    Connection(CecConnectionResultError),
+    #[error("{}", match .0 {
+        TryFromCecAudioStatusError::Unknown => "unknown audio status",
+        TryFromCecAudioStatusError::Reserved(_) => "reserved audio status",
+    })]
+    AudioStatus(TryFromCecAudioStatusError),
+    #[error("{}", match .0 {
+        TryFromCecLogicalAddressesError::UnknownPrimaryAddress => "no logical address",
+        TryFromCecLogicalAddressesError::InvalidPrimaryAddress => "invalid logical address",
+    })]
+    LogicalAddresses(TryFromCecLogicalAddressesError),
+    #[error("{0}")]
+    Log(String),
+}
+
+impl From<CecConnectionResultError> for CecError {
+    fn from(value: CecConnectionResultError) -> Self {
+        Self::Connection(value)
+    }
+}
+
+impl From<TryFromCecAudioStatusError> for CecError {
+    fn from(value: TryFromCecAudioStatusError) -> Self {
+        Self::AudioStatus(value)
+    }
+}
+
+impl From<TryFromCecLogicalAddressesError> for CecError {
+    fn from(value: TryFromCecLogicalAddressesError) -> Self {
+        Self::LogicalAddresses(value)
+    }
+}
@@ -0,0 +1,248 @@
+use {
+    crate::{macro_command::DeckInfo, scene::Scene},
+    async_channel::{Receiver, Sender, TrySendError},
+    async_io::Timer,
+    blocking::{Task, unblock},
+    futures_util::{FutureExt, future::poll_fn},
+    serde::{Deserialize, Serialize},
+    std::{
+        cell::{Cell, Ref, RefCell},
+        collections::BTreeMap,
+        convert::Infallible,
+        env, fs,
+        io::{self, ErrorKind},
+        path::{Path, PathBuf},
+        task::Poll,
+        time::Duration,
+    },
+};
+
+/// Daemon state that's kept across restarts
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct State {
+    /// Last audio status reported by the TV / AVR
+    pub audio: Option<Audio>,
+
+    /// Logical address of the last known active source
+    pub active_source: Option<u8>,
+
+    /// Physical address of the last known active source
+    pub active_source_physical_address: Option<u16>,
+
+    /// Last power status reported by the TV
+    pub tv_power: Option<bool>,
+
+    pub mpris: Mpris,
+
+    /// Scenes saved with `cec-sync scene save`, by name
+    pub scenes: BTreeMap<String, Scene>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Audio {
+    pub volume: u8,
+    pub muted: bool,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Mpris {
+    /// Deck info last sent to the CEC bus
+    pub deck_info: DeckInfo,
+
+    /// Running players by MPRIS bus name
+    pub players: BTreeMap<String, Player>,
+
+    /// Settings of every player seen so far, restored when they start
+    /// again, by MPRIS bus name without the instance suffix
+    pub preferences: BTreeMap<String, Preferences>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Player {
+    pub playback_status: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Preferences {
+    pub shuffle: Option<bool>,
+
+    /// `None`, `Track` or `Playlist`
+    pub loop_status: Option<String>,
+}
+
+/// State backed by a file in the state directory
+pub struct Store {
+    path: Option<PathBuf>,
+    state: RefCell<State>,
+    subscribers: RefCell<Vec<Sender<()>>>,
+
+    /// Changed since it was last saved
+    dirty: Cell<bool>,
+    changed_tx: Sender<()>,
+    changed: Receiver<()>,
+    /// Write that's still running, kept here so that it's waited for
+    /// even if the flush that started it was cancelled
+    saving: RefCell<Option<Task<Result<(), Error>>>>,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new(None, State::default())
+    }
+}
+
+impl Store {
+    /// How long to wait after a change before saving, so that bursts of
+    /// changes (eg. while the volume is turned up) are saved at once
+    const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+    pub fn path() -> Option<PathBuf> {
+        // Set by systemd when the service has a StateDirectory=
+        let mut state_path = env::var_os("STATE_DIRECTORY")
+            .map(PathBuf::from)
+            .or_else(|| {
+                env::var_os("XDG_STATE_HOME")
+                    .map(|state_home| PathBuf::from(state_home).join("cec-sync"))
+            })
+            .or_else(|| env::home_dir().map(|home| home.join(".local/state/cec-sync")))?;
+
+        state_path.push("state.toml");
+        Some(state_path)
+    }
+
+    pub fn load() -> Result<Self, Error> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+
+        let state = match fs::read_to_string(&path) {
+            Ok(contents) => {
+                toml::from_str(&contents).map_err(|err| Error::Parse(path.clone(), err))?
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => State::default(),
+            Err(err) => return Err(Error::Read(path, err)),
+        };
+
+        Ok(Self::new(Some(path), state))
+    }
+
+    /// State saved to `path` when it changes, or only kept in memory
+    pub fn new(path: Option<PathBuf>, state: State) -> Self {
+        let (changed_tx, changed) = async_channel::bounded(1);
+        Self {
+            path,
+            state: RefCell::new(state),
+            subscribers: RefCell::default(),
+            dirty: Cell::new(false),
+            changed_tx,
+            changed,
+            saving: RefCell::new(None),
+        }
+    }
+
+    pub fn get(&self) -> Ref<'_, State> {
+        self.state.borrow()
+    }
+
+    /// Get notified whenever the state changes
+    ///
+    /// Changes made while the last notification wasn't received yet are
+    /// only notified once.
+    pub fn subscribe(&self) -> Receiver<()> {
+        let (tx, rx) = async_channel::bounded(1);
+        self.subscribers.borrow_mut().push(tx);
+        rx
+    }
+
+    /// Modify the state, marking it to be saved if anything changed
+    ///
+    /// The state is saved by [`Store::save_changes`] or [`Store::flush`].
+    pub fn update(&self, f: impl FnOnce(&mut State)) {
+        let mut state = self.state.borrow_mut();
+        let old_state = state.clone();
+        f(&mut state);
+        if *state == old_state {
+            return;
+        }
+
+        self.dirty.set(true);
+        let _ = self.changed_tx.try_send(());
+        self.subscribers
+            .borrow_mut()
+            .retain(|tx| !matches!(tx.try_send(()), Err(TrySendError::Closed(_))));
+    }
+
+    /// Save the changes in the background, a moment after they're made
+    pub async fn save_changes(&self) -> Infallible {
+        loop {
+            // The channel lives as long as the store
+            let _ = self.changed.recv().await;
+            Timer::after(Self::SAVE_DELAY).await;
+            self.flush().await;
+        }
+    }
+
+    /// Save the state now if it changed
+    ///
+    /// Persisting state is best effort, so failures are only logged.
+    pub async fn flush(&self) {
+        self.saved().await;
+        if !self.dirty.replace(false) {
+            return;
+        }
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+
+        let state = self.state.borrow().clone();
+        self.saving
+            .replace(Some(unblock(move || Self::save(&path, &state))));
+        self.saved().await;
+    }
+
+    /// Wait for the last write to finish
+    async fn saved(&self) {
+        let result = poll_fn(|cx| match &mut *self.saving.borrow_mut() {
+            Some(task) => task.poll_unpin(cx).map(Some),
+            None => Poll::Ready(None),
+        })
+        .await;
+        self.saving.replace(None);
+
+        if let Some(Err(err)) = result {
+            eprintln!("notice: {err}, continuing without saving state...");
+        }
+    }
+
+    fn save(path: &Path, state: &State) -> Result<(), Error> {
+        let write = || {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+
+            // Write to a temporary file first, so that the state is
+            // never left half written
+            let tmp_path = path.with_extension("toml.tmp");
+            // Serializing plain data should never fail
+            fs::write(&tmp_path, toml::to_string(state).unwrap())?;
+            fs::rename(&tmp_path, path)
+        };
+
+        write().map_err(|err| Error::Write(path.to_owned(), err))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to read {}: {}", .0.display(), .1)]
+    Read(PathBuf, io::Error),
+    #[error("failed to parse {}: {}", .0.display(), .1)]
+    Parse(PathBuf, toml::de::Error),
+    #[error("failed to write {}: {}", .0.display(), .1)]
+    Write(PathBuf, io::Error),
+}